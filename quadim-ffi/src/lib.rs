@@ -4,8 +4,12 @@ use quadim::*;
 use std::slice;
 
 /// 处理一帧 RGBA u8 数据，in-out 原地处理
+///
+/// # Safety
+///
+/// Same as [`quadim_process_rgba_u8_blend`].
 #[unsafe(no_mangle)]
-pub extern "C" fn quadim_process_rgba_u8(
+pub unsafe extern "C" fn quadim_process_rgba_u8(
     data: *mut u8,
     width: u32,
     height: u32,
@@ -17,6 +21,47 @@ pub extern "C" fn quadim_process_rgba_u8(
     thres_cbcr: u8,
     merge_method: u32,
     shape: u32,
+) -> i32 {
+    unsafe {
+        quadim_process_rgba_u8_blend(
+            data,
+            width,
+            height,
+            buffer_size,
+            ratio_w,
+            ratio_h,
+            max_depth,
+            thres_ay,
+            thres_cbcr,
+            merge_method,
+            shape,
+            0,
+            255,
+        )
+    }
+}
+
+/// 同上，但风格化的结果会以 `blend_mode`（见 `BlendMode`）和 `opacity`（255 为完全不透明）叠加到原图上
+///
+/// # Safety
+///
+/// `data` must point to `width * height * 4` bytes of RGBA that are valid for reads and writes,
+/// and not accessed by anything else during the call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn quadim_process_rgba_u8_blend(
+    data: *mut u8,
+    width: u32,
+    height: u32,
+    buffer_size: usize,
+    ratio_w: u8,
+    ratio_h: u8,
+    max_depth: u8,
+    thres_ay: u8,
+    thres_cbcr: u8,
+    merge_method: u32,
+    shape: u32,
+    blend_mode: u32,
+    opacity: u8,
 ) -> i32 {
     // 安全检查
     let pixels = (width as usize) * (height as usize) * 4;
@@ -29,10 +74,9 @@ pub extern "C" fn quadim_process_rgba_u8(
         let mut canvas = vec![(0u8, SampleType::zeros()); buffer_size];
 
         // 参数封装
-        let gp = GenericParams {
-            slicing_ratio: (ratio_w, ratio_h),
-            max_depth,
-        };
+        let brush_enum: ClassicBrush = (shape as u8).into();
+        let gp = GenericParams::new((ratio_w, ratio_h), max_depth)
+            .with_gradient(brush_enum == ClassicBrush::Smooth);
         let ap = AnalyzeParams {
             thres_ay: thres_ay as f32,
             thres_cbcr: thres_cbcr as f32,
@@ -49,16 +93,14 @@ pub extern "C" fn quadim_process_rgba_u8(
         }
 
         // 渲染
-        let brush: Box<dyn Brush> = {
-            let brush_enum: ClassicBrush = (shape as u8).into();
-            Box::new(brush_enum)
-        };
-        let rp = RenderParams {
-            bg_color: PixelType::from([255, 255, 255, 255]),
-            stroke_color: PixelType::from([0, 0, 0, 255]),
-            stroke_width: 1,
-            seed: 0,
-        };
+        let brush: Box<dyn Brush> = Box::new(brush_enum);
+        let rp = RenderParams::new(
+            PixelType::from([255, 255, 255, 255]),
+            PixelType::from([0, 0, 0, 255]),
+            1,
+        )
+        .with_blend((blend_mode as u8).into())
+        .with_opacity(opacity);
 
         // 参数顺序：image, canvas, brush, generic, render params, elapsed time
         if render(&mut img, &canvas, brush, gp, rp, 0.0).is_err() {
@@ -73,8 +115,12 @@ pub extern "C" fn quadim_process_rgba_u8(
 }
 
 /// 简化版接口：只传 data/width/height/size，其他都用默认 CLI 参数
+///
+/// # Safety
+///
+/// Same as [`quadim_process_rgba_u8_blend`].
 #[unsafe(no_mangle)]
-pub extern "C" fn quadim_process_rgba_u8_default(
+pub unsafe extern "C" fn quadim_process_rgba_u8_default(
     data: *mut u8,
     width: u32,
     height: u32,
@@ -89,17 +135,19 @@ pub extern "C" fn quadim_process_rgba_u8_default(
     const MERGE_METHOD: u32 = 1;
     const SHAPE: u32 = 0;
 
-    quadim_process_rgba_u8(
-        data,
-        width,
-        height,
-        buffer_size,
-        RATIO_W,
-        RATIO_H,
-        MAX_DEPTH,
-        THRES_AY,
-        THRES_CBCR,
-        MERGE_METHOD,
-        SHAPE,
-    )
+    unsafe {
+        quadim_process_rgba_u8(
+            data,
+            width,
+            height,
+            buffer_size,
+            RATIO_W,
+            RATIO_H,
+            MAX_DEPTH,
+            THRES_AY,
+            THRES_CBCR,
+            MERGE_METHOD,
+            SHAPE,
+        )
+    }
 }
@@ -68,11 +68,7 @@ impl MergeMethod {
         match merge_method {
             MergeMethod::Range => chall
                 .iter()
-                .zip(
-                    aycbcrs
-                        .row_iter()
-                        .map(|ch| ch.max() as f32 - ch.min() as f32),
-                )
+                .zip(aycbcrs.row_iter().map(|ch| ch.max() - ch.min()))
                 .any(|(&chall, range)| range > chall),
 
             MergeMethod::StDev => chall
@@ -93,6 +89,7 @@ pub fn analyze(
     let GenericParams {
         slicing_ratio,
         max_depth,
+        gradient,
     } = ge_params;
 
     let (img_w, img_h) = img.dimensions();
@@ -114,6 +111,7 @@ pub fn analyze(
                     start_at,
                     area_size,
                     an_params,
+                    gradient,
                     real_max_depth,
                     None,
                 );
@@ -123,12 +121,14 @@ pub fn analyze(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn go_depth(
     img: &ImageType,
     canvas: CanvasViewMut,
     start_at: (u32, u32),
     area_size: (u32, u32),
     an_params: AnalyzeParams,
+    gradient: bool,
     max_depth: u8,
     now_depth: Option<u8>,
 ) -> Option<SampleType> {
@@ -138,8 +138,11 @@ fn go_depth(
 
     let now_depth = now_depth.unwrap_or(1);
 
+    // 四个子块的平均颜色，用于拟合渐变
+    let mut quads: Option<[SampleAltType; FOUR::usize]> = None;
+
     let avg_color: SampleType = if now_depth < max_depth {
-        let colors: [SampleType; FOUR::usize] = match util::div_quad(start_at, area_size)
+        let colors: [SampleType; FOUR::usize] = util::div_quad(start_at, area_size)
             .map(|(start_at, area_size)| {
                 go_depth(
                     img,
@@ -147,29 +150,46 @@ fn go_depth(
                     start_at,
                     area_size,
                     an_params,
+                    gradient,
                     max_depth,
                     Some(now_depth + 1),
                 )
             })
             .fuse()
-            .filter_map(std::convert::identity)
+            .flatten()
             .collect::<Vec<_>>()
             .try_into()
-            .ok()
-        {
-            Some(cs) => cs,
-            None => return None,
-        };
+            .ok()?;
 
         match MergeMethod::is_fluctuated(colors, an_params) {
             true => return None,
-            false => (colors
-                .iter()
-                .fold(SampleAltType::zeros(), |acc, c| acc + c.cast())
-                / FOUR::f32)
-                .try_cast()
-                .unwrap(),
+            false => {
+                quads = Some(colors.map(|c| c.cast()));
+                (colors
+                    .iter()
+                    .fold(SampleAltType::zeros(), |acc, c| acc + c.cast())
+                    / FOUR::f32)
+                    .try_cast()
+                    .unwrap()
+            }
         }
+    } else if gradient && w > 1 && h > 1 {
+        let mut sum = SampleAltType::zeros();
+        quads = util::div_quad(start_at, area_size)
+            .map(|((qx, qy), (qw, qh))| {
+                let quad = img
+                    .view(qx, qy, qw, qh)
+                    .pixels()
+                    .fold(SampleAltType::zeros(), |acc, (_, _, p)| {
+                        acc + SampleType::from(p.0).cast()
+                    });
+                sum += quad;
+                quad / (qw * qh) as f32
+            })
+            .collect::<Vec<_>>()
+            .try_into()
+            .ok();
+        (sum / (w * h) as f32).try_cast().unwrap()
     } else {
         (img.view(sx, sy, w, h)
             .pixels()
@@ -184,6 +204,13 @@ fn go_depth(
     let (d, c) = &mut canvas[util::pos(img_w, sx, sy)];
     *d = now_depth;
     *c = avg_color;
+
+    if let Some([tl, tr, bl, br]) = quads.filter(|_| gradient) {
+        let (slot_x, slot_y) = util::gradient_slots(img_w, start_at);
+        canvas[slot_x] = (now_depth, util::encode_slope((tr - tl) + (br - bl)));
+        canvas[slot_y] = (now_depth, util::encode_slope((bl - tl) + (br - tr)));
+    }
+
     Some(avg_color)
 }
 
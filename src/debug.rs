@@ -0,0 +1,96 @@
+use super::*;
+
+/// A brush for tuning thresholds, which colors each node by its depth and outlines it.
+///
+/// The outline uses the stroke color, alignment, style and width (at least 1 pixel) on all nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthBrush {
+    pub colormap: Colormap,
+    /// The depth mapped to the end of the colormap, usually [`GenericParams::max_depth`].
+    pub max_depth: u8,
+}
+
+/// Maps a value in `0.0..=1.0` to a color.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Colormap {
+    Turbo,
+    Viridis,
+    Gray,
+}
+
+impl Colormap {
+    pub fn map(self, t: f32) -> PixelType {
+        let t = t.clamp(0., 1.);
+        // 多项式拟合
+        let poly = |c: [f32; 7]| c.iter().rev().fold(0., |acc, &k| acc * t + k);
+
+        let rgb = match self {
+            Colormap::Turbo => [
+                poly([0.1357, 4.6154, -42.6603, 132.1311, -152.9424, 59.2864, 0.]),
+                poly([0.0914, 2.1942, 4.8430, -14.1850, 4.2773, 2.8296, 0.]),
+                poly([0.1067, 12.6419, -60.5820, 110.3628, -89.9031, 27.3482, 0.]),
+            ],
+            Colormap::Viridis => [
+                poly([0.2777, 0.1051, -0.3309, -4.6342, 6.2283, 4.7764, -5.4355]),
+                poly([0.0054, 1.4046, 0.2148, -5.7991, 14.1799, -13.7451, 4.6459]),
+                poly([0.3341, 1.3846, 0.0951, -19.3324, 56.6906, -65.3530, 26.3124]),
+            ],
+            Colormap::Gray => [t, t, t],
+        };
+
+        let [r, g, b] = rgb.map(|c| (c * 255.).round().clamp(0., 255.) as u8);
+        PixelType::from([r, g, b, 255])
+    }
+}
+
+impl Brush for DepthBrush {
+    fn paint(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        (sx, sy): (u32, u32),
+        (w, h): (u32, u32),
+        _time_elapsed: f32,
+        now_depth: u8,
+        _color: PixelType,
+    ) {
+        use imageproc::{drawing::draw_filled_rect_mut, rect::Rect};
+
+        let t = match self.max_depth {
+            0 | 1 => 0.,
+            n => (now_depth.saturating_sub(1)) as f32 / (n - 1) as f32,
+        };
+        let (sx, sy) = (sx as i32, sy as i32);
+        draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), self.colormap.map(t));
+        let params = RenderParams {
+            stroke_width: params.stroke_width.max(1),
+            ..params
+        };
+        draw_rect_stroke(img, (sx, sy), (w, h), params, params.stroke_color);
+    }
+
+    fn need_background(&self) -> bool {
+        false
+    }
+}
+
+/// Count the leaves in the canvas from [`analyze()`] for each depth.
+///
+/// The N-th element is the number of leaves at depth N+1.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image size and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn depth_histogram(
+    canvas: CanvasView,
+    img_size: (u32, u32),
+    ge_params: GenericParams,
+) -> Vec<usize> {
+    let mut histogram = vec![0usize; ge_params.max_depth as usize];
+    for_each_leaf(canvas, img_size, ge_params, |leaf| {
+        histogram[leaf.depth as usize - 1] += 1;
+    });
+    histogram
+}
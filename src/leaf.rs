@@ -0,0 +1,64 @@
+use super::*;
+
+/// A leaf node of the quadtree, as stored in the canvas by [`analyze()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Leaf {
+    pub start_at: (u32, u32),
+    pub area_size: (u32, u32),
+    pub depth: u8,
+    pub color: PixelType,
+}
+
+/// Visit every leaf in the canvas from [`analyze()`], in the same order as [`render()`] paints them.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image size and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn for_each_leaf<F: FnMut(Leaf)>(
+    canvas: CanvasView,
+    img_size: (u32, u32),
+    ge_params: GenericParams,
+    mut f: F,
+) {
+    let GenericParams {
+        slicing_ratio,
+        max_depth,
+        gradient: _,
+    } = ge_params;
+
+    for Tile {
+        start_at,
+        area_size,
+        real_max_depth: _,
+    } in util::div_grid(img_size, slicing_ratio, max_depth)
+    {
+        go_depth(canvas, img_size.0, start_at, area_size, None, &mut f);
+    }
+}
+
+fn go_depth<F: FnMut(Leaf)>(
+    canvas: CanvasView,
+    img_w: u32,
+    start_at: (u32, u32),
+    area_size: (u32, u32),
+    now_depth: Option<u8>,
+    f: &mut F,
+) {
+    let (sx, sy) = start_at;
+
+    let now_depth = now_depth.unwrap_or(1);
+
+    let (d, c) = canvas[util::pos(img_w, sx, sy)];
+    if now_depth < d {
+        for (start_at, area_size) in util::div_quad(start_at, area_size) {
+            go_depth(canvas, img_w, start_at, area_size, Some(now_depth + 1), f);
+        }
+    } else {
+        f(Leaf {
+            start_at,
+            area_size,
+            depth: now_depth,
+            color: PixelType::from(Into::<[DepthType; CHANNEL_COUNT]>::into(c)),
+        });
+    }
+}
@@ -5,12 +5,28 @@ use nalgebra::{ArrayStorage, Const, Matrix};
 use thiserror::Error;
 
 mod analyze;
+mod debug;
+mod leaf;
+mod metrics;
+mod palette;
+mod quadtree;
 mod render;
+#[cfg(feature = "rhai")]
+mod script;
+mod search;
+mod sprite;
+mod text;
 mod util;
 
 use util::Tile;
 
-pub use self::{analyze::*, render::*};
+pub use self::{
+    analyze::*, debug::*, leaf::*, metrics::*, palette::*, quadtree::*, render::*, search::*,
+    sprite::*, text::*,
+};
+
+#[cfg(feature = "rhai")]
+pub use self::script::*;
 
 pub type DepthType = u8;
 pub type PixelType = Rgba<DepthType>;
@@ -35,6 +51,9 @@ mod FOUR {
 }
 
 /// Parameters required by both [`analyze()`] and [`render()`].
+///
+/// Made with [`GenericParams::new()`], so that adding a field is not a breaking change.
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct GenericParams {
     /// Specifies how to slice the image into sub-blocks.
@@ -43,4 +62,42 @@ pub struct GenericParams {
     ///
     /// (Don't worry about the performance being affected by too large a value ;)
     pub max_depth: u8,
+    /// Also retain a linear color gradient for each node, for brushes such as [`ClassicBrush::Smooth`].
+    ///
+    /// The gradient is fitted from the average colors of the four quadrants of each node.
+    pub gradient: bool,
+}
+
+impl GenericParams {
+    /// Without gradients, see [`GenericParams::with_gradient()`].
+    pub fn new(slicing_ratio: (u8, u8), max_depth: u8) -> Self {
+        Self {
+            slicing_ratio,
+            max_depth,
+            gradient: false,
+        }
+    }
+
+    /// Sets [`GenericParams::gradient`].
+    pub fn with_gradient(self, gradient: bool) -> Self {
+        Self { gradient, ..self }
+    }
+}
+
+/// The change of each channel across a node:
+/// from its left edge to its right edge (`dx`), and from its top edge to its bottom edge (`dy`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gradient {
+    pub dx: SampleAltType,
+    pub dy: SampleAltType,
+}
+
+impl Gradient {
+    /// The color at `(u, v)` relative to the node (both in `0.0..=1.0`), whose average color is `color`.
+    pub fn at(&self, color: PixelType, u: f32, v: f32) -> PixelType {
+        let c = SampleType::from(color.0).cast::<f32>() + self.dx * (u - 0.5) + self.dy * (v - 0.5);
+        PixelType::from(Into::<[DepthType; CHANNEL_COUNT]>::into(
+            c.map(|ch| ch.round().clamp(0., 255.) as DepthType),
+        ))
+    }
 }
@@ -1,4 +1,10 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use clap::Parser;
 
@@ -11,14 +17,52 @@ struct Args {
     /* ----- 路径 ----- */
     /// The image to process, or all images in a directory to process.
     ///
-    /// Note that IO from Stdio is currently not supported.
-    #[arg(required = true, value_name = "IMAGE_OR_DIR")]
+    /// Use "-" to read an image from stdin.
+    #[arg(
+        required = false,
+        required_unless_present = "dump_config",
+        value_name = "IMAGE_OR_DIR",
+        // 只是为了 "--dump-config" 可以不给 IMAGE，不给的话还是会报错
+        default_value = "-",
+        hide_default_value = true
+    )]
     src: PathBuf,
     /// Leave blank to automatically create a time-based named DST, or specify manually.
     ///
-    /// Note that IO from Stdio is currently not supported.
+    /// Use "-" to write to stdout, in the format of "--format" (PNG by default).
     #[arg(short = 'o', long = "output", value_name = "IMAGE_OR_DIR")]
     dst: Option<PathBuf>,
+    /// Also process the images in all subdirectories, recreating the directory structure under DST.
+    ///
+    /// DST is created if it does not exist.
+    #[arg(long, conflicts_with_all = ["video", "raw_size"])]
+    recursive: bool,
+    /// Only process the files matching this glob in the directory, e.g. "*.png" or "icons/**/*.jpg".
+    ///
+    /// The glob is matched against the path relative to IMAGE_OR_DIR, and "*" also matches "/".
+    /// Specify multiple times to match any of them.
+    /// By default, all files (or with "--recursive", all files with an image extension).
+    #[arg(long, value_name = "GLOB", value_parser = globset::Glob::new)]
+    include: Vec<globset::Glob>,
+    /// Keep watching IMAGE_OR_DIR, and process the images again as soon as they change.
+    ///
+    /// All images are processed again when the config file changes, with the options reloaded.
+    #[arg(long, requires = "dst", conflicts_with_all = ["video", "raw_size"])]
+    watch: bool,
+
+    /// Treat IMAGE and the output as video files, decoded and encoded by `ffmpeg` (and `ffprobe`) in PATH.
+    ///
+    /// Frames are processed in parallel, and written in order. The audio is copied.
+    /// Brushes change over time at the frame rate of the video.
+    #[arg(long, requires = "dst", conflicts_with_all = ["frames", "raw_size"])]
+    video: bool,
+    /// Read raw RGBA frames of this size ("WxH") from stdin, and write raw RGBA frames
+    /// (of "--output-size", if specified) to stdout.
+    ///
+    /// IMAGE must be "-". E.g. `ffmpeg ... -f rawvideo -pix_fmt rgba - | quadim - --raw-size 1920x1080 | ffmpeg -f rawvideo ...`.
+    /// Brushes change over time at "--fps".
+    #[arg(long, value_name = "SIZE", value_parser = Self::parse_raw_size, conflicts_with = "frames")]
+    raw_size: Option<(u32, u32)>,
 
     /* ----- 通用参数 ----- */
     /// Specifies how to slice the image into sub-blocks.
@@ -31,6 +75,11 @@ struct Args {
     /// (Don't worry about the performance being affected by too large a value ;)
     #[arg(short = 'd', long = "depth", value_parser = clap::value_parser!(u8).range(1..), default_value_t = 8)]
     max_depth: u8,
+    /// Analyze the image at this size, for speed. Either "WxH" or a scale factor like "0.5x".
+    ///
+    /// The output is still the size of the input image, unless "--output-size" is specified.
+    #[arg(long, value_name = "SIZE", value_parser = Self::parse_size)]
+    analyze_size: Option<ImageSize>,
 
     /* ----- 分析参数 ----- */
     /// Thresholding on Alpha and Luma channels.
@@ -53,6 +102,14 @@ struct Args {
     /// - This parameter will only have a weak impact on performance.
     #[arg(short = 'C', long, default_value_t = 2.)]
     thres_cbcr: f32,
+    /// Search for the thresholds of each image ("-Y" and "-C", scaled together),
+    /// so that the nodes are at least this close to the image in PSNR (dB), with as few nodes as possible.
+    #[arg(long, value_name = "DB", conflicts_with = "target_leaves")]
+    target_psnr: Option<f64>,
+    /// Search for the thresholds of each image ("-Y" and "-C", scaled together),
+    /// so that there are at most this many nodes, with as many details as possible.
+    #[arg(long, value_name = "N")]
+    target_leaves: Option<usize>,
 
     /// Specifies the algorithm to use for merging tests.
     ///
@@ -62,11 +119,43 @@ struct Args {
     #[arg(long = "merge", default_value = "st-dev")]
     merge_method: MergeMethod,
 
+    /// Snap the colors of nodes to a palette.
+    ///
+    /// Either a number N to compute N colors from each image, a palette file (".gpl", ".hex"),
+    /// or a list of CSS colors separated by commas.
+    #[arg(long, value_parser = Self::parse_palette)]
+    palette: Option<PaletteSpec>,
+
     /* ----- 渲染参数 ----- */
     /// Specifies the shape used to depict each node on the quadtree.
     #[arg(short = 's', long = "shape", default_value = "rect")]
     brush: ClassicBrush,
 
+    /// Use your own art as the brush: stamp this sprite into each node, colored by the node.
+    ///
+    /// Specify multiple times to use a different sprite for each depth (the last one for deeper nodes).
+    ///
+    /// Overrides "--shape".
+    #[arg(long = "sprite", value_name = "IMAGE")]
+    sprites: Vec<PathBuf>,
+    /// Specifies how to scale the sprites.
+    #[arg(long, default_value = "bilinear")]
+    sprite_filter: SpriteFilter,
+    /// Specifies how to combine the sprites with the color of nodes.
+    ///
+    /// "tint" keeps only the luminance of the sprites.
+    #[arg(long, default_value = "multiply")]
+    sprite_blend: SpriteBlend,
+
+    /// Paint each node with a brush scripted in Rhai.
+    ///
+    /// The script sees `x, y, w, h, depth, color, time, seed` of each node, and paints with
+    /// `fill_rect, stroke_rect, fill_ellipse, stroke_ellipse, line, pixel`.
+    ///
+    /// Overrides "--shape" and "--sprite".
+    #[arg(long, value_name = "FILE")]
+    brush_script: Option<PathBuf>,
+
     /// The background color of the fill (if required).
     #[arg(short = 'B', long, value_parser = Self::parse_color, default_value = "white")]
     bg_color: PixelType,
@@ -78,17 +167,88 @@ struct Args {
     /// The width of the stroke.
     #[arg(short = 'W', long, default_value_t = 0)]
     stroke_width: u32,
+    /// Where the stroke lies relative to the edges of nodes.
+    ///
+    /// "inner" also strokes the frame of the image fully, "outer" does not stroke it at all.
+    #[arg(long, default_value = "center")]
+    stroke_align: StrokeAlign,
+    /// The pattern of the stroke.
+    #[arg(long, default_value = "solid")]
+    stroke_style: StrokeStyle,
+    /// Only stroke nodes at least this deep, to outline the details only.
+    #[arg(long, value_name = "DEPTH", default_value_t = 0)]
+    stroke_min_depth: u8,
+    /// Draw the edges between nodes as a grid over the brush, instead of stroking each node.
+    ///
+    /// Every edge is drawn once with the same width (at least 1 pixel), including the frame of the image.
+    #[arg(long)]
+    grid: bool,
+
+    /// How to blend the styled image over the original one.
+    #[arg(long, default_value = "normal")]
+    blend: BlendMode,
+    /// The opacity of the styled image over the original one, from 0 to 1.
+    #[arg(long, value_parser = Self::parse_opacity, default_value = "1")]
+    opacity: u8,
 
     /// Make your brushes change over time!
+    ///
+    /// Also the frame rate of "--frames".
     #[arg(long = "fps", value_parser = Self::parse_framerate, default_value_t = 30.)]
     framerate: f32,
 
-    /// (reserved)
+    /// The random seed for randomized brushes ("jitter", "rotate", "shift", "scatter") and scripted brushes.
     ///
-    /// 自定义笔刷的随机数种子。
-    #[arg(hide = true, long, default_value_t = 0)]
+    /// The same seed always produces the same result.
+    #[arg(long, default_value_t = 0)]
     seed: u64,
 
+    /* ----- 输出参数 ----- */
+    /// The format of the output. By default, it follows the extension of DST, or PNG if unknown.
+    ///
+    /// "text" and "ansi" depict the quadtree as text art, which can be previewed in a terminal.
+    #[arg(long)]
+    format: Option<OutputFormat>,
+    /// The quality of JPEG, from 1 to 100.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), default_value_t = 90)]
+    quality: u8,
+    /// The compression level of PNG. "best" makes smaller files, but takes longer.
+    #[arg(long, default_value = "fast")]
+    png_compression: PngCompression,
+    /// Write an animation of this many frames for each image, to see brushes change over time (see "--fps").
+    ///
+    /// The image is analyzed only once. Animated PNG for "png", animated GIF for "gif".
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    frames: Option<u32>,
+    /// Render the output at this size, e.g. for large prints. Either "WxH" or a scale factor like "2x".
+    ///
+    /// The scale factor is relative to the input image.
+    #[arg(long, value_name = "SIZE", value_parser = Self::parse_size)]
+    output_size: Option<ImageSize>,
+    /// The width of text art, in characters.
+    #[arg(long, default_value_t = 80)]
+    columns: u32,
+    /// Specifies the characters used to depict text art.
+    #[arg(long, default_value = "blocks")]
+    charset: TextCharset,
+
+    /* ----- 调试 ----- */
+    /// Color each node by its depth and outline it, to see how deep the quadtree went.
+    ///
+    /// Overrides all the other brushes.
+    #[arg(long)]
+    debug_depth: bool,
+    /// The colormap used by "--debug-depth".
+    #[arg(long, default_value = "turbo")]
+    colormap: Colormap,
+    /// Print the number of nodes at each depth, for each image.
+    #[arg(long)]
+    histogram: bool,
+    /// Print the error of the output from each image (MSE, PSNR and SSIM), and the error of the nodes
+    /// (each filled with its color, whatever the brush) from the image analyzed.
+    #[arg(long)]
+    metrics: bool,
+
     /* ----- 杂项 ----- */
     /// Specifies the number of threads to use. The default is the number of CPU logical cores.
     #[arg(short = 'P', long = "parallel")]
@@ -101,9 +261,503 @@ struct Args {
     /// Error count, when this many errors have occurred, Quadim will terminate early.
     #[arg(long = "errors", value_parser = Self::parse_errth, default_value_t = 5)]
     max_errors: usize,
+    /// Skip the images whose output already exists and is newer than the image.
+    #[arg(long)]
+    skip_existing: bool,
+    /// Record each image written in this file, and skip the images recorded with the same parameters
+    /// (and unchanged since), so that an aborted batch can be resumed by running it again.
+    ///
+    /// Specify DST too, or the time-based named DST will be a new one each time.
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+    /// Write the statistics of each image to this JSON file: the number of nodes (at each depth),
+    /// the error from the image (MSE, PSNR and SSIM), the time taken, and the options used.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["video", "raw_size"])]
+    report: Option<PathBuf>,
+
+    /* ----- 配置 ----- */
+    /// Load options from this TOML file, where the keys are the long names of options,
+    /// e.g. `depth = 6`, `stroke-width = 2` or `sprite = ["a.png", "b.png"]`.
+    /// The options on the command line take precedence.
+    ///
+    /// By default, "$XDG_CONFIG_HOME/quadim/config.toml" (or "~/.config/quadim/config.toml") if it exists.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Also load the options in the table `[presets.NAME]` of the config file, over the others in it.
+    #[arg(long, value_name = "NAME")]
+    preset: Option<String>,
+    /// Print the effective options as TOML, which can be used as a config file or a preset, then exit.
+    #[arg(long)]
+    dump_config: bool,
+    /// The effective options, as in a config file.
+    #[arg(skip)]
+    options: toml::Table,
+}
+
+// 不写子命令就是 `run`，所以 `quadim IMAGE ...` 还是原来的样子
+#[derive(Parser, Debug, Clone)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: Args,
+}
+
+/// All subcommands take the same options (and config file), but use only those they need.
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Process images, which is the default. `quadim run IMAGE` is the same as `quadim IMAGE`.
+    Run(Args),
+    /// Analyze IMAGE, and save the quadtree to a file, to be rendered by `render` later.
+    ///
+    /// The file is next to IMAGE with the extension ".qdt" by default.
+    /// Only the options of analysis are used, e.g. "--depth", "--thres-ay", "--analyze-size" and "--palette".
+    Analyze(Args),
+    /// Render a quadtree file from `analyze` (as IMAGE) with any brush, as if the image were analyzed again.
+    ///
+    /// The output is next to the file by default, e.g. "photo.render.png" for "photo.qdt". The options of analysis are ignored,
+    /// and blending is over a blank image, since the image is not in the file.
+    Render(Args),
+    /// Print the statistics of the quadtree of IMAGE, which is either an image to analyze, or a quadtree file.
+    Inspect(Args),
+    /// Decode, analyze, render and encode IMAGE over and over, and print how long each stage takes
+    /// (minimum, median and 99th percentile), then compare the merge methods and the shapes.
+    ///
+    /// IMAGE is read only once. The options are used as in `run`, e.g. "--depth", "--shape" and "--format".
+    /// The current merge method and shape are marked with "*".
+    Bench {
+        /// How many times to run each stage, merge method and shape.
+        #[arg(short = 'n', long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 100)]
+        iterations: u32,
+        #[command(flatten)]
+        args: Args,
+    },
+}
+
+impl Cli {
+    /// Parse the command line over the config file (and the preset), or exit with an error.
+    ///
+    /// With "--dump-config", print the options and exit.
+    fn load() -> Command {
+        let command = Self::try_load().unwrap_or_else(|e| e.exit());
+        let args = command.args();
+        if args.dump_config {
+            print!("{}", toml::to_string(&args.options).unwrap_or_default());
+            std::process::exit(0)
+        }
+        command
+    }
+
+    /// Parse the command line over the config file (and the preset).
+    ///
+    /// "--config" is set to the config file loaded, if any.
+    fn try_load() -> Result<Command, clap::Error> {
+        use clap::{
+            error::ErrorKind, parser::ValueSource, ArgAction, CommandFactory, FromArgMatches,
+        };
+
+        let cli = std::env::args_os().collect::<Vec<_>>();
+        let mut command = Self::command();
+        let matches = command.clone().try_get_matches_from(&cli)?;
+        // 子命令的选项在它自己那里，而子命令一定是第一个参数
+        let subcommand = matches.subcommand_name().map(str::to_owned);
+        let (args_command, args_matches) = match &subcommand {
+            Some(name) => (
+                command.find_subcommand(name).unwrap().clone(),
+                matches.subcommand_matches(name).unwrap(),
+            ),
+            None => (command.clone(), &matches),
+        };
+
+        let config = match args_matches.get_one::<PathBuf>("config") {
+            Some(path) => Some(path.clone()),
+            None => Args::default_config().filter(|p| p.is_file()),
+        };
+        let preset = args_matches.get_one::<String>("preset");
+
+        // 配置文件里的选项变成命令行参数，放在真正的命令行参数前面
+        let mut from_config = Vec::new();
+        match (&config, preset) {
+            (Some(path), _) => {
+                let table = match Args::read_config(path, preset) {
+                    Ok(table) => table,
+                    Err(e) => {
+                        return Err(
+                            command.error(ErrorKind::Io, format!("failed to load {path:?}: {e}"))
+                        )
+                    }
+                };
+                for (key, value) in table {
+                    let long = key.replace('_', "-");
+                    let Some(arg) = args_command.get_arguments().find(|a| {
+                        a.get_long() == Some(&long)
+                            && !Args::NOT_IN_CONFIG.contains(&a.get_id().as_str())
+                    }) else {
+                        return Err(command.error(
+                            ErrorKind::UnknownArgument,
+                            format!("unknown option `{key}` in {path:?}"),
+                        ));
+                    };
+                    if args_matches.value_source(arg.get_id().as_str())
+                        == Some(ValueSource::CommandLine)
+                    {
+                        continue;
+                    }
+
+                    let values = match value {
+                        toml::Value::Array(values) => values,
+                        value => vec![value],
+                    };
+                    for value in values {
+                        let value = match value {
+                            toml::Value::Boolean(b)
+                                if matches!(arg.get_action(), ArgAction::SetTrue) =>
+                            {
+                                if b {
+                                    from_config.push(format!("--{long}").into());
+                                }
+                                continue;
+                            }
+                            toml::Value::String(s) => s,
+                            toml::Value::Integer(_)
+                            | toml::Value::Float(_)
+                            | toml::Value::Boolean(_) => value.to_string(),
+                            _ => {
+                                return Err(command.error(
+                                    ErrorKind::InvalidValue,
+                                    format!("invalid value of `{key}` in {path:?}"),
+                                ))
+                            }
+                        };
+                        from_config.push(format!("--{long}={value}").into());
+                    }
+                }
+            }
+            (None, Some(_)) => {
+                return Err(command.error(
+                    ErrorKind::MissingRequiredArgument,
+                    "\"--preset\" needs a config file, see \"--config\"",
+                ))
+            }
+            (None, None) => (),
+        }
+
+        let at = 1 + subcommand.is_some() as usize;
+        let matches = command
+            .try_get_matches_from_mut(cli[..at].iter().chain(&from_config).chain(&cli[at..]))?;
+        let (mut parsed, args_matches) = match matches.subcommand() {
+            Some((_, sub)) => (Command::from_arg_matches(&matches)?, sub),
+            None => (Command::Run(Args::from_arg_matches(&matches)?), &matches),
+        };
+        let args = parsed.args_mut();
+        args.config = config;
+        args.options = Args::options(&args_command, args_matches);
+        Ok(parsed)
+    }
+}
+
+impl Command {
+    fn args(&self) -> &Args {
+        match self {
+            Command::Run(args)
+            | Command::Analyze(args)
+            | Command::Render(args)
+            | Command::Inspect(args)
+            | Command::Bench { args, .. } => args,
+        }
+    }
+
+    fn args_mut(&mut self) -> &mut Args {
+        match self {
+            Command::Run(args)
+            | Command::Analyze(args)
+            | Command::Render(args)
+            | Command::Inspect(args)
+            | Command::Bench { args, .. } => args,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum PaletteSpec {
+    /// Computed from each image.
+    Auto(usize),
+    Fixed(Palette),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImageSize {
+    Exact(u32, u32),
+    Scale(f32),
+}
+
+impl ImageSize {
+    fn resolve(self, (w, h): (u32, u32)) -> (u32, u32) {
+        match self {
+            ImageSize::Exact(w, h) => (w, h),
+            ImageSize::Scale(f) => (
+                ((w as f32 * f).round() as u32).max(1),
+                ((h as f32 * f).round() as u32).max(1),
+            ),
+        }
+    }
+}
+
+/// Everything a worker needs, other than the parameters of the library.
+#[derive(Debug, Clone)]
+struct Extras {
+    analyze_size: Option<ImageSize>,
+    output_size: Option<ImageSize>,
+    palette: Option<PaletteSpec>,
+    histogram: bool,
+    metrics: bool,
+    report: bool,
+    target: Option<Target>,
+    grid: bool,
+    format: OutputFormat,
+    quality: u8,
+    png_compression: PngCompression,
+    frames: Option<u32>,
+    framerate: f32,
+    te_params: TextParams,
+}
+
+/// Specifies the format of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// PNG image.
+    Png,
+    /// JPEG image, see "--quality".
+    Jpeg,
+    /// Lossless WebP image.
+    Webp,
+    /// QOI image.
+    Qoi,
+    /// TIFF image.
+    Tiff,
+    /// BMP image.
+    Bmp,
+    /// GIF image.
+    Gif,
+    /// Plain text art.
+    Text,
+    /// Text art colored with ANSI truecolor escapes.
+    Ansi,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Text => "txt",
+            OutputFormat::Ansi => "ans",
+        }
+    }
+
+    fn from_extension(ext: &std::ffi::OsStr) -> Option<Self> {
+        Some(match ext.to_str()?.to_ascii_lowercase().as_str() {
+            "png" | "apng" => OutputFormat::Png,
+            "jpg" | "jpeg" => OutputFormat::Jpeg,
+            "webp" => OutputFormat::Webp,
+            "qoi" => OutputFormat::Qoi,
+            "tif" | "tiff" => OutputFormat::Tiff,
+            "bmp" => OutputFormat::Bmp,
+            "gif" => OutputFormat::Gif,
+            "txt" => OutputFormat::Text,
+            "ans" => OutputFormat::Ansi,
+            _ => return None,
+        })
+    }
+
+    /// Where an image of this format is written for DST. The extension is kept if it already
+    /// corresponds to this format, e.g. ".jpeg".
+    fn output_path(self, mut dst: PathBuf) -> PathBuf {
+        if dst.extension().and_then(OutputFormat::from_extension) != Some(self) {
+            dst.set_extension(self.extension());
+        }
+        dst
+    }
+
+    /// Encode a still image. Text art is not handled here.
+    fn encode(
+        self,
+        img: &ImageType,
+        quality: u8,
+        png_compression: PngCompression,
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        use image::{
+            codecs::{jpeg::JpegEncoder, png::PngEncoder},
+            ColorType, DynamicImage, ImageEncoder, ImageOutputFormat,
+        };
+
+        let (w, h) = img.dimensions();
+        let mut cursor = std::io::Cursor::new(bytes);
+        match self {
+            OutputFormat::Png => PngEncoder::new_with_quality(
+                cursor,
+                png_compression.into(),
+                image::codecs::png::FilterType::Adaptive,
+            )
+            .write_image(img.as_raw(), w, h, ColorType::Rgba8)?,
+            // JPEG 没有透明度
+            OutputFormat::Jpeg => JpegEncoder::new_with_quality(cursor, quality)
+                .encode_image(&DynamicImage::ImageRgba8(img.clone()).into_rgb8())?,
+            OutputFormat::Webp => image_webp::WebPEncoder::new(cursor).encode(
+                img.as_raw(),
+                w,
+                h,
+                image_webp::ColorType::Rgba8,
+            )?,
+            OutputFormat::Qoi => img.write_to(&mut cursor, ImageOutputFormat::Qoi)?,
+            OutputFormat::Tiff => img.write_to(&mut cursor, ImageOutputFormat::Tiff)?,
+            OutputFormat::Bmp => img.write_to(&mut cursor, ImageOutputFormat::Bmp)?,
+            OutputFormat::Gif => img.write_to(&mut cursor, ImageOutputFormat::Gif)?,
+            OutputFormat::Text | OutputFormat::Ansi => unreachable!(),
+        }
+        Ok(())
+    }
+}
+
+/// Specifies the compression level of PNG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl From<PngCompression> for image::codecs::png::CompressionType {
+    fn from(c: PngCompression) -> Self {
+        match c {
+            PngCompression::Fast => Self::Fast,
+            PngCompression::Default => Self::Default,
+            PngCompression::Best => Self::Best,
+        }
+    }
+}
+
+impl From<PngCompression> for png::Compression {
+    fn from(c: PngCompression) -> Self {
+        match c {
+            PngCompression::Fast => Self::Fast,
+            PngCompression::Default => Self::Default,
+            PngCompression::Best => Self::Best,
+        }
+    }
 }
 
 impl Args {
+    /// These can not be in config files.
+    const NOT_IN_CONFIG: [&'static str; 5] = ["src", "dst", "config", "preset", "dump_config"];
+    /// The keys of [`Args::options`] that don't affect the output of an image.
+    /// "format" is replaced by the one resolved from DST.
+    const NOT_IN_FINGERPRINT: [&'static str; 13] = [
+        "recursive",
+        "include",
+        "watch",
+        "histogram",
+        "metrics",
+        "parallel",
+        "buffer",
+        "errors",
+        "skip-existing",
+        "manifest",
+        "report",
+        "dump-config",
+        "format",
+    ];
+
+    fn default_config() -> Option<PathBuf> {
+        let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("quadim").join("config.toml"))
+    }
+
+    /// The top-level options of the config file, overridden by those of the preset.
+    fn read_config(path: &Path, preset: Option<&String>) -> Result<toml::Table, Box<dyn Error>> {
+        let mut table = std::fs::read_to_string(path)?.parse::<toml::Table>()?;
+        let presets = table.remove("presets");
+        if let Some(name) = preset {
+            let preset = presets
+                .as_ref()
+                .and_then(|p| p.get(name))
+                .and_then(toml::Value::as_table)
+                .ok_or_else(|| format!("no preset named `{name}`"))?;
+            table.extend(preset.clone());
+        }
+        Ok(table)
+    }
+
+    /// The options (including the default ones) as a config file.
+    fn options(command: &clap::Command, matches: &clap::ArgMatches) -> toml::Table {
+        use clap::ArgAction;
+
+        // 看起来像数字的就写成数字
+        fn to_value(s: &std::ffi::OsStr) -> toml::Value {
+            let s = s.to_string_lossy();
+            if let Ok(i) = s.parse::<i64>() {
+                toml::Value::Integer(i)
+            } else if let Some(f) = s
+                .parse::<f64>()
+                .ok()
+                .filter(|_| s.chars().all(|c| c.is_ascii_digit() || "+-.".contains(c)))
+            {
+                toml::Value::Float(f)
+            } else {
+                toml::Value::String(s.into_owned())
+            }
+        }
+
+        let mut table = toml::Table::new();
+        for arg in command.get_arguments() {
+            let id = arg.get_id().as_str();
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            if Self::NOT_IN_CONFIG.contains(&id) {
+                continue;
+            }
+            let value = match arg.get_action() {
+                ArgAction::SetTrue => toml::Value::Boolean(matches.get_flag(id)),
+                ArgAction::Set | ArgAction::Append => {
+                    let Some(mut values) = matches
+                        .get_raw(id)
+                        .map(|v| v.map(to_value).collect::<Vec<_>>())
+                    else {
+                        continue;
+                    };
+                    match arg.get_action() {
+                        ArgAction::Append => toml::Value::Array(values),
+                        _ => match values.pop() {
+                            Some(value) => value,
+                            None => continue,
+                        },
+                    }
+                }
+                _ => continue,
+            };
+            table.insert(long.into(), value);
+        }
+        table
+    }
+
     fn parse_ratio(s: &str) -> Result<(u8, u8), &'static str> {
         const MSG: &str =
             "the format of `ratio` must be `W:H` where W and H are both positive integers";
@@ -119,224 +773,1867 @@ impl Args {
         Ok(PixelType::from(csscolorparser::parse(s)?.to_rgba8()))
     }
 
+    fn parse_palette(s: &str) -> Result<PaletteSpec, PaletteError> {
+        if let Ok(n) = s.parse::<usize>() {
+            return match n {
+                0 => Err(PaletteError::Empty),
+                n => Ok(PaletteSpec::Auto(n)),
+            };
+        }
+        Ok(PaletteSpec::Fixed(
+            match std::path::Path::new(s).is_file() {
+                true => Palette::load(s)?,
+                false => Palette::parse(s)?,
+            },
+        ))
+    }
+
+    fn parse_opacity(s: &str) -> Result<u8, &'static str> {
+        const MSG: &str = "`opacity` must be a float between 0 and 1";
+        s.parse::<f32>()
+            .ok()
+            .filter(|o| (0. ..=1.).contains(o))
+            .map(|o| (o * 255.).round() as u8)
+            .ok_or(MSG)
+    }
+
+    fn parse_size(s: &str) -> Result<ImageSize, &'static str> {
+        const MSG: &str =
+            "the format of size must be `WxH` where W and H are both positive integers, or `Nx` where N is a positive float";
+        if let Some(f) = s.strip_suffix(['x', 'X']) {
+            return f
+                .parse::<f32>()
+                .ok()
+                .filter(|&f| f > 0.)
+                .map(ImageSize::Scale)
+                .ok_or(MSG);
+        }
+        let (w, h) = s.split_once(['x', 'X']).ok_or(MSG)?;
+        match (w.parse::<u32>(), h.parse::<u32>()) {
+            (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok(ImageSize::Exact(w, h)),
+            _ => Err(MSG),
+        }
+    }
+
+    fn parse_raw_size(s: &str) -> Result<(u32, u32), &'static str> {
+        match Self::parse_size(s) {
+            Ok(ImageSize::Exact(w, h)) => Ok((w, h)),
+            _ => Err("the format of size must be `WxH` where W and H are both positive integers"),
+        }
+    }
+
     fn parse_framerate(s: &str) -> Result<f32, &'static str> {
         const MSG: &str = "`framerate` must be a float greater than zero";
         s.parse::<f32>().ok().filter(|&f| f > 0.).ok_or(MSG)
     }
 
     fn parse_errth(s: &str) -> Result<usize, std::num::ParseIntError> {
-        Ok(match usize::from_str_radix(s, 10)? {
+        Ok(match s.parse::<usize>()? {
             0 => usize::MAX,
             n => n,
         })
     }
 
-    fn to_params(&self) -> (GenericParams, AnalyzeParams, RenderParams, Box<dyn Brush>) {
-        (
-            GenericParams {
-                slicing_ratio: self.slicing_ratio,
+    /// Any of the globs of "--include", if specified.
+    fn include(&self) -> Result<Option<globset::GlobSet>, globset::Error> {
+        if self.include.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = globset::GlobSetBuilder::new();
+        self.include.iter().cloned().for_each(|g| {
+            builder.add(g);
+        });
+        builder.build().map(Some)
+    }
+
+    /// "--format", or following the extension of DST.
+    fn format(&self) -> OutputFormat {
+        self.format
+            .or_else(|| {
+                self.dst
+                    .as_ref()
+                    .and_then(|p| p.extension())
+                    .and_then(OutputFormat::from_extension)
+            })
+            .unwrap_or(OutputFormat::Png)
+    }
+
+    fn to_brush(&self) -> Result<Arc<dyn Brush>, Box<dyn Error>> {
+        if self.debug_depth {
+            return Ok(Arc::new(DepthBrush {
+                colormap: self.colormap,
                 max_depth: self.max_depth,
-            },
+            }));
+        }
+        if let Some(path) = &self.brush_script {
+            return Ok(Arc::new(ScriptBrush::new(&std::fs::read_to_string(path)?)?));
+        }
+        if !self.sprites.is_empty() {
+            return Ok(Arc::new(SpriteBrush::open(
+                &self.sprites,
+                self.sprite_filter,
+                self.sprite_blend,
+            )?));
+        }
+        Ok(Arc::new(self.brush))
+    }
+
+    fn to_params(
+        &self,
+        brush: &Arc<dyn Brush>,
+    ) -> (GenericParams, AnalyzeParams, RenderParams, Arc<dyn Brush>) {
+        (
+            GenericParams::new(self.slicing_ratio, self.max_depth)
+                .with_gradient(self.brush == ClassicBrush::Smooth),
             AnalyzeParams {
                 thres_ay: self.thres_ay,
                 thres_cbcr: self.thres_cbcr,
                 merge_method: self.merge_method,
             },
-            RenderParams {
-                bg_color: self.bg_color,
-                stroke_color: self.stroke_color,
-                stroke_width: self.stroke_width,
-                seed: self.seed,
-            },
-            Box::new(self.brush),
+            RenderParams::new(self.bg_color, self.stroke_color, self.stroke_width)
+                .with_stroke_align(self.stroke_align)
+                .with_stroke_style(self.stroke_style)
+                .with_stroke_min_depth(self.stroke_min_depth)
+                .with_blend(self.blend)
+                .with_opacity(self.opacity)
+                .with_seed(self.seed),
+            brush.clone(),
         )
     }
-}
-
-fn main() {
-    use std::{
-        error::Error,
-        io::{self, Write},
-        process::exit,
-        sync::{mpsc, Arc},
-        time::Instant,
-    };
-
-    use object_pool::Pool;
-    use threadpool::ThreadPool;
 
-    use src_dst_clarifier::*;
+    /// A hash of everything that affects the output of an image, for "--manifest".
+    fn fingerprint(&self) -> String {
+        use clap::ValueEnum;
 
-    fn err_cast(e: Box<dyn Error>) -> String {
-        if let Some(e) = e.downcast_ref::<image::ImageError>() {
-            return format!("(ImageError) {e}");
-        } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
-            let k = e.kind();
-            return format!("({k:?}) {e}");
-        } else {
-            return format!("({e:?}) {e}");
+        // FNV-1a 和 TOML 的值都不随编译器和依赖的版本变化（`DefaultHasher` 和 `Debug` 的输出则不然），
+        // 只有选项本身增减或者改名时，指纹才会改变
+        let mut hash = 0xcbf29ce484222325u64;
+        let mut feed = |bytes: &[u8]| {
+            for &b in bytes {
+                hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+            }
+        };
+        let mut options = self
+            .options
+            .iter()
+            .filter(|(key, _)| !Self::NOT_IN_FINGERPRINT.contains(&key.as_str()))
+            .collect::<Vec<_>>();
+        options.sort_unstable_by_key(|(key, _)| key.as_str());
+        for (key, value) in options {
+            feed(format!("{key} = {value}\n").as_bytes());
         }
-    }
-
-    let args = Args::parse();
-
-    let mut sdpairs = match || -> Result<SrcDstPairs, Box<dyn Error>> {
-        let ps = SrcDstConfig {
-            allow_from_stdin: false,
-            allow_to_stdout: false,
-            auto_tnamed_dst_file: true,
-            auto_tnamed_dst_dir: true,
-            default_extension: "png".into(),
-            allow_inplace: false,
+        if let Some(format) = self.format().to_possible_value() {
+            feed(format!("format = {:?}\n", format.get_name()).as_bytes());
         }
-        .parse(&args.src, args.dst.as_ref())??;
-        ps.create_tnamed_dir()?;
-        Ok(ps)
-    }() {
-        Ok(ps) => ps,
-        Err(e) => {
-            eprintln!("FATAL: {}.", err_cast(e.into()));
-            exit(3)
+        // 笔刷文件的内容也算在内
+        for path in self.sprites.iter().chain(&self.brush_script) {
+            feed(&std::fs::read(path).unwrap_or_default());
         }
-    };
+        format!("{hash:016x}")
+    }
 
-    let (tx, rx) = mpsc::channel::<Result<(), Box<dyn Error + Send + Sync>>>();
+    fn to_extras(&self) -> Extras {
+        Extras {
+            analyze_size: self.analyze_size,
+            output_size: self.output_size,
+            palette: self.palette.clone(),
+            histogram: self.histogram,
+            metrics: self.metrics,
+            report: self.report.is_some(),
+            target: match (self.target_psnr, self.target_leaves) {
+                (Some(psnr), _) => Some(Target::Psnr(psnr)),
+                (_, Some(n)) => Some(Target::Leaves(n)),
+                (None, None) => None,
+            },
+            grid: self.grid,
+            format: self.format(),
+            quality: self.quality,
+            png_compression: self.png_compression,
+            frames: self.frames,
+            framerate: self.framerate,
+            te_params: TextParams {
+                columns: self.columns,
+                charset: self.charset,
+                ansi: self.format() == OutputFormat::Ansi,
+            },
+        }
+    }
+}
 
-    let num_threads = match sdpairs.is_batch() {
-        false => 1,
-        true => match args.parallelism {
-            Some(n) => n,
-            None => num_cpus::get(),
-        },
-    };
-    let buffer_size = match args.buffer_size {
-        Some(n) => n,
-        None => match sdpairs.is_batch() {
-            true => 1920 * 1080,
-            false => 7680 * 4320,
-        },
-    };
+/// Which images have been done already, for "--skip-existing" and "--manifest".
+#[derive(Debug, Default)]
+struct Resume {
+    skip_existing: bool,
+    fingerprint: String,
+    /// SRC => (DST, fingerprint, modified time of SRC)，后面的记录覆盖前面的
+    recorded: HashMap<PathBuf, (PathBuf, String, String)>,
+}
 
-    let thread_pool = ThreadPool::new(num_threads);
-    let canvas_pool = Arc::new(Pool::<Box<[CanvasPixel]>>::new(num_threads, || {
-        vec![(0u8, SampleType::zeros()); buffer_size].into_boxed_slice()
-    }));
+impl Resume {
+    const MANIFEST_HEADER: &'static str = "# src\tdst\tparameters\tmodified";
 
-    fn worker(
-        tx: mpsc::Sender<Result<(), Box<dyn Error + Send + Sync>>>,
-        (src, dst): (Src, Dst),
-        canvas_pool: Arc<Pool<Box<[CanvasPixel]>>>,
-        (ge_params, an_params, re_params, brush): (
-            GenericParams,
-            AnalyzeParams,
-            RenderParams,
-            Box<dyn Brush>,
-        ),
-        time_elapsed: f32,
-    ) {
-        tx.send((|| {
-            let src = match src {
-                Src::File(p) => p,
-                Src::Stdin => unreachable!(),
-            };
-            let mut dst = match dst {
-                Dst::File(p) => p,
-                Dst::Stdout => unreachable!(),
+    fn new(args: &Args) -> io::Result<Self> {
+        let mut recorded = HashMap::new();
+        if let Some(manifest) = &args.manifest {
+            let content = match std::fs::read_to_string(manifest) {
+                Ok(content) => content,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e),
             };
+            for line in content.lines().filter(|l| !l.starts_with('#')) {
+                if let [src, dst, fingerprint, modified] = line.split('\t').collect::<Vec<_>>()[..]
+                {
+                    recorded.insert(
+                        src.into(),
+                        (dst.into(), fingerprint.into(), modified.into()),
+                    );
+                }
+            }
+        }
 
-            let mut img = Into::<ImageType>::into(image::open(src)?.into_rgba8());
-
-            let mut canvas = canvas_pool.try_pull().unwrap();
-
-            analyze(&img, &mut canvas, ge_params, an_params)?;
-            render(&mut img, &canvas, brush, ge_params, re_params, time_elapsed)?;
-
-            dst.set_extension("png");
-            img.save(dst)?;
-
-            Ok(())
-        })())
-        .unwrap();
+        Ok(Self {
+            skip_existing: args.skip_existing,
+            fingerprint: args.fingerprint(),
+            recorded,
+        })
     }
 
-    let t_started = Instant::now();
+    /// The modified time of the file, as recorded in the manifest.
+    fn modified(path: &Path) -> io::Result<String> {
+        let t = std::fs::metadata(path)?
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(format!("{}.{:09}", t.as_secs(), t.subsec_nanos()))
+    }
 
-    let fps = args.framerate;
-    let mut tot = 0f32;
+    /// Whether the output of SRC has been written to DST (the path with the extension of the format).
+    fn is_done(&self, src: &Path, dst: &Path) -> bool {
+        let Ok(dst_meta) = std::fs::metadata(dst) else {
+            return false;
+        };
+        if self.skip_existing {
+            let newer =
+                || Some(dst_meta.modified().ok()? >= std::fs::metadata(src).ok()?.modified().ok()?);
+            if newer() == Some(true) {
+                return true;
+            }
+        }
+        match self.recorded.get(src) {
+            Some((recorded_dst, fingerprint, modified)) => {
+                recorded_dst == dst
+                    && *fingerprint == self.fingerprint
+                    && Self::modified(src).is_ok_and(|m| m == *modified)
+            }
+            None => false,
+        }
+    }
 
-    let err_max = args.max_errors;
-    let mut err_ctr = 0usize;
-    let mut succ_ctr = 0usize;
-    let mut milestone = 1usize;
-    const MILESTONE_INTERVAL: usize = 500;
+    /// A line of the manifest, or `None` if the paths can not be recorded.
+    fn record(&self, src: &Path, dst: &Path) -> Option<String> {
+        let (src, dst) = (src.to_str()?, dst.to_str()?);
+        if [src, dst].iter().any(|p| p.contains(['\t', '\n', '\r'])) {
+            return None;
+        }
+        let modified = Self::modified(src.as_ref()).ok()?;
+        Some(format!("{src}\t{dst}\t{}\t{modified}\n", self.fingerprint))
+    }
+}
 
-    let mut done = false;
+/// An image analyzed once, which can then be rendered at any time.
+struct Analyzed<'a> {
+    canvas: &'a [CanvasPixel],
+    size: (u32, u32),
+    /// 渲染的底图，已经是输出的尺寸
+    base: ImageType,
+    /// 输出尺寸的原图，用来和输出比较（仅当 "--report" 或 "--metrics"）
+    source: Option<ImageType>,
+    /// The error of the nodes from the image analyzed (only with "--metrics").
+    flat: Option<Quality>,
+    /// The parameters analyzed with, which are searched for with "--target-psnr" or "--target-leaves".
+    an_params: AnalyzeParams,
+    /// The PSNR reached instead, if "--target-psnr" is out of reach (e.g. with too few colors in "--palette").
+    missed_psnr: Option<f64>,
+}
 
-    let mut stderr = io::stderr();
-    let mut exit_code = 0i32;
+impl<'a> Analyzed<'a> {
+    /// Analyze the image (downscaled first if "--analyze-size"), then snap it to the palette (if "--palette").
+    fn new(
+        mut img: ImageType,
+        canvas: &'a mut [CanvasPixel],
+        (ge_params, an_params, re_params): (GenericParams, AnalyzeParams, RenderParams),
+        extras: &Extras,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        use image::imageops::{resize, FilterType};
 
-    loop {
-        while thread_pool.queued_count() > 0 {}
+        // 分析前缩小的话，保留原图用于输出
+        let src_size = img.dimensions();
+        let out_size = extras.output_size.map_or(src_size, |s| s.resolve(src_size));
+        let mut original = None;
+        if let Some(size) = extras.analyze_size.map(|s| s.resolve(src_size)) {
+            if size != src_size {
+                let resized = resize(&img, size.0, size.1, FilterType::Triangle);
+                original = Some(std::mem::replace(&mut img, resized));
+            }
+        }
+        let size = img.dimensions();
 
-        match sdpairs.next() {
-            Some(sdpair) => {
-                let tx = tx.clone();
-                let canvas_pool = canvas_pool.clone();
-                let params = args.to_params();
-                let time_elapsed = tot / fps;
-                thread_pool.execute(move || worker(tx, sdpair, canvas_pool, params, time_elapsed));
+        // 搜索阈值时量化后再衡量，目标才是对最终结果而言的
+        let snap = |canvas: CanvasViewMut| -> Result<(), Box<dyn Error + Send + Sync>> {
+            if let Some(spec) = &extras.palette {
+                let palette = match spec {
+                    PaletteSpec::Auto(n) => Palette::from_canvas(canvas, size, ge_params, *n)?,
+                    PaletteSpec::Fixed(p) => p.clone(),
+                };
+                quantize(canvas, size, ge_params, &palette);
             }
+            Ok(())
+        };
+        let an_params = match extras.target {
+            Some(target) => analyze_for_with(&img, canvas, ge_params, an_params, target, snap)?,
             None => {
-                thread_pool.join();
-                done = true;
+                analyze(&img, canvas, ge_params, an_params)?;
+                snap(canvas)?;
+                an_params
             }
-        }
-
-        tot += 1.;
+        };
 
-        while let Ok(msg) = rx.try_recv() {
-            match msg {
-                Ok(_) => {
-                    succ_ctr += 1;
-                    eprint!(".");
-                }
-                Err(e) => {
-                    err_ctr += 1;
-                    exit_code = 1;
-                    eprintln!("\nERROR ({err_ctr}/{err_max}): {}.", err_cast(e));
-                }
+        let flat = match extras.metrics {
+            true => Some(compare_canvas(&img, canvas, ge_params)?),
+            false => None,
+        };
+        let missed_psnr = match extras.target {
+            Some(Target::Psnr(psnr)) => {
+                Some(canvas_psnr(&img, canvas, ge_params)?).filter(|&reached| reached < psnr)
             }
-        }
+            _ => None,
+        };
 
-        if succ_ctr > milestone * MILESTONE_INTERVAL {
-            eprintln!(
-                "\nINFO: {} images have been processed.",
-                milestone * MILESTONE_INTERVAL
-            );
-            milestone += 1;
-        }
+        let resized = |o: &ImageType| match o.dimensions() == out_size {
+            true => o.clone(),
+            false => resize(o, out_size.0, out_size.1, FilterType::Triangle),
+        };
+        let source =
+            (extras.report || extras.metrics).then(|| resized(original.as_ref().unwrap_or(&img)));
 
-        if err_ctr >= err_max {
-            exit_code = 2;
-            eprintln!("\nFATAL: Too many errors ({err_max}/{err_max}).");
-            break;
+        // 混合时以原图为底，否则从空白开始
+        let base = match (re_params.blend, re_params.opacity) {
+            _ if size == out_size => img,
+            (BlendMode::Normal, 255) => ImageType::new(out_size.0, out_size.1),
+            _ => match original.unwrap_or(img) {
+                o if o.dimensions() == out_size => o,
+                o => resized(&o),
+            },
+        };
+
+        Ok(Self {
+            canvas,
+            size,
+            base,
+            source,
+            flat,
+            an_params,
+            missed_psnr,
+        })
+    }
+
+    /// Render a frame at the given time onto `base` (taken from [`Analyzed::base`], or a clone of it for animations).
+    /// With "--grid", the brush does not stroke by itself.
+    fn render(
+        &self,
+        mut base: ImageType,
+        brush: &Arc<dyn Brush>,
+        ge_params: GenericParams,
+        re_params: RenderParams,
+        grid: bool,
+        time_elapsed: f32,
+    ) -> Result<ImageType, RenderError> {
+        let mut brush_params = re_params;
+        if grid {
+            brush_params.stroke_width = 0;
         }
 
-        if done {
-            break;
+        render_scaled(
+            &mut base,
+            self.size,
+            self.canvas,
+            Box::new(brush.clone()),
+            ge_params,
+            brush_params,
+            time_elapsed,
+        )?;
+        if grid {
+            render_grid(&mut base, self.size, self.canvas, ge_params, re_params);
         }
+        Ok(base)
+    }
+}
+
+type Pairs = Box<dyn Iterator<Item = (src_dst_clarifier::Src, src_dst_clarifier::Dst)> + Send>;
 
-        stderr.flush().ok();
+fn err_cast(e: Box<dyn Error>) -> String {
+    if let Some(e) = e.downcast_ref::<image::ImageError>() {
+        format!("(ImageError) {e}")
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        let k = e.kind();
+        format!("({k:?}) {e}")
+    } else {
+        format!("({e:?}) {e}")
     }
+}
 
-    let t_finished = Instant::now();
-    let t_used = (t_finished - t_started).as_secs_f32();
-    eprintln!(
+/// An encoded image, with the messages to print and the statistics for "--report".
+struct Output {
+    bytes: Vec<u8>,
+    info: String,
+    stats: Option<serde_json::Value>,
+}
+
+fn decode(src: &src_dst_clarifier::Src) -> Result<ImageType, Box<dyn Error + Send + Sync>> {
+    use std::io::Read;
+
+    use src_dst_clarifier::Src;
+
+    let img = match src {
+        Src::File(p) => image::open(p)?,
+        Src::Stdin => {
+            let mut buf = Vec::new();
+            io::stdin().lock().read_to_end(&mut buf)?;
+            image::load_from_memory(&buf)?
+        }
+    };
+    Ok(Into::<ImageType>::into(img.into_rgba8()))
+}
+
+/// Analyze, render and encode an image.
+fn process(
+    img: ImageType,
+    src: &Path,
+    canvas: &mut [CanvasPixel],
+    params: (GenericParams, AnalyzeParams, RenderParams, Arc<dyn Brush>),
+    extras: Extras,
+    time_elapsed: f32,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    use std::time::Instant;
+
+    let (ge_params, an_params, re_params, _) = params;
+    let t_started = Instant::now();
+    let analyzed = Analyzed::new(img, canvas, (ge_params, an_params, re_params), &extras)?;
+    let t_analyze = t_started.elapsed();
+
+    encode(analyzed, src, params, extras, time_elapsed, t_analyze)
+}
+
+/// Render and encode an image analyzed, which took `t_analyze` (for "--report").
+fn encode(
+    mut analyzed: Analyzed,
+    src: &Path,
+    (ge_params, _, re_params, brush): (GenericParams, AnalyzeParams, RenderParams, Arc<dyn Brush>),
+    extras: Extras,
+    time_elapsed: f32,
+    t_analyze: std::time::Duration,
+) -> Result<Output, Box<dyn Error + Send + Sync>> {
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+
+    let t_started = Instant::now();
+
+    // 静态图直接画在底图上，只有动画的每一帧才需要复制一份
+    let base = std::mem::take(&mut analyzed.base);
+    let analyzed = &analyzed;
+
+    let counts = (extras.histogram || extras.report)
+        .then(|| depth_histogram(analyzed.canvas, analyzed.size, ge_params));
+    let mut info = String::new();
+    if let Some(counts) = counts.as_ref().filter(|_| extras.histogram) {
+        info += &format!("\nINFO: Nodes at each depth of {src:?}:\n");
+        info += &histogram_bars(counts);
+    }
+
+    let Extras {
+        grid,
+        format,
+        quality,
+        png_compression,
+        frames,
+        framerate,
+        te_params,
+        ..
+    } = extras;
+
+    let t_render = Cell::new(Duration::ZERO);
+    let t_compare = Cell::new(Duration::ZERO);
+    let fidelity = Cell::new(None);
+    let render_frame =
+        |base: ImageType, time_elapsed: f32| -> Result<ImageType, Box<dyn Error + Send + Sync>> {
+            let t_started = Instant::now();
+            let frame = analyzed.render(base, &brush, ge_params, re_params, grid, time_elapsed)?;
+            t_render.set(t_render.get() + t_started.elapsed());
+            // 动画只和第一帧比较
+            if let (Some(source), None) = (&analyzed.source, fidelity.get()) {
+                let t_started = Instant::now();
+                fidelity.set(Some(compare(&frame, source)?));
+                t_compare.set(t_started.elapsed());
+            }
+            Ok(frame)
+        };
+
+    // 先编码到内存里，这样写到文件和 stdout 是一样的
+    let mut bytes = Vec::new();
+    // 动画只分析一次，之后每一帧按时间重新渲染
+    let frame_time = |i: u32| time_elapsed + i as f32 / framerate;
+    match (format, frames) {
+        (OutputFormat::Text | OutputFormat::Ansi, _) => {
+            bytes = render_text(analyzed.canvas, analyzed.size, ge_params, te_params).into_bytes();
+        }
+        (_, None) => format.encode(
+            &render_frame(base, time_elapsed)?,
+            quality,
+            png_compression,
+            &mut bytes,
+        )?,
+        (OutputFormat::Gif, Some(n)) => {
+            use image::{
+                codecs::gif::{GifEncoder, Repeat},
+                Delay, Frame,
+            };
+
+            let mut encoder = GifEncoder::new(&mut bytes);
+            encoder.set_repeat(Repeat::Infinite)?;
+            let delay = Delay::from_saturating_duration(Duration::from_secs_f32(1. / framerate));
+            for i in 0..n {
+                let frame = render_frame(base.clone(), frame_time(i))?;
+                encoder.encode_frame(Frame::from_parts(frame, 0, 0, delay))?;
+            }
+        }
+        (_, Some(n)) => {
+            let (w, h) = base.dimensions();
+            let mut encoder = png::Encoder::new(&mut bytes, w, h);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(png_compression.into());
+            encoder.set_animated(n, 0)?;
+            // 单位是毫秒
+            encoder.set_frame_delay(
+                (1000. / framerate).round().clamp(1., u16::MAX as f32) as u16,
+                1000,
+            )?;
+            let mut writer = encoder.write_header()?;
+            for i in 0..n {
+                writer.write_image_data(render_frame(base.clone(), frame_time(i))?.as_raw())?;
+            }
+            writer.finish()?;
+        }
+    }
+
+    let fidelity: Option<Quality> = fidelity.get();
+    if extras.target.is_some() {
+        let AnalyzeParams {
+            thres_ay,
+            thres_cbcr,
+            ..
+        } = analyzed.an_params;
+        info += &format!("\nINFO: Thresholds for {src:?}: -Y {thres_ay:.3} -C {thres_cbcr:.3}\n");
+        if let Some(reached) = analyzed.missed_psnr {
+            info += &format!("INFO: The target PSNR is out of reach, {reached:.2} dB instead.\n");
+        }
+    }
+    if extras.metrics {
+        info += &format!("\nINFO: Error from {src:?}:\n");
+        let rows = [("output", fidelity), ("nodes", analyzed.flat)];
+        for (name, q) in rows.into_iter().filter_map(|(n, q)| Some((n, q?))) {
+            info += &format!(
+                "{name:>8} | MSE {:>8.2} | PSNR {:>6.2} dB | SSIM {:.4}\n",
+                q.mse, q.psnr, q.ssim
+            );
+        }
+    }
+
+    let stats = counts.filter(|_| extras.report).map(|counts| {
+        let ms = |t: Duration| t.as_secs_f64() * 1000.;
+        serde_json::json!({
+            "leaves": counts.iter().sum::<usize>(),
+            "histogram": counts,
+            "thres_ay": analyzed.an_params.thres_ay,
+            "thres_cbcr": analyzed.an_params.thres_cbcr,
+            "mse": fidelity.map(|q| q.mse),
+            "psnr": fidelity.map(|q| q.psnr),
+            "ssim": fidelity.map(|q| q.ssim),
+            "analyze_ms": ms(t_analyze),
+            "render_ms": ms(t_render.get()),
+            "encode_ms": ms(t_started
+                .elapsed()
+                .saturating_sub(t_render.get() + t_compare.get())),
+        })
+    });
+
+    Ok(Output { bytes, info, stats })
+}
+
+/// The number of nodes at each depth, with bars.
+fn histogram_bars(counts: &[usize]) -> String {
+    let peak = counts.iter().copied().max().unwrap_or(0).max(1);
+    let mut bars = String::new();
+    for (d, &n) in counts.iter().enumerate() {
+        let bar = "#".repeat((n * 40).div_ceil(peak));
+        bars += format!("{:>5} | {n:>8} {bar}", d + 1).trim_end();
+        bars += "\n";
+    }
+    bars
+}
+
+fn write(dst: src_dst_clarifier::Dst, format: OutputFormat, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+
+    use src_dst_clarifier::Dst;
+
+    match dst {
+        Dst::File(p) => std::fs::write(format.output_path(p), bytes),
+        Dst::Stdout => io::stdout().lock().write_all(bytes),
+    }
+}
+
+fn main() {
+    use std::{process::exit, time::Instant};
+
+    // 子命令只做一件事，出错了就结束
+    fn exit_code(result: Result<(), Box<dyn Error>>) -> i32 {
+        match result {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("FATAL: {}.", err_cast(e));
+                3
+            }
+        }
+    }
+
+    let args = match Cli::load() {
+        Command::Run(args) => args,
+        Command::Analyze(args) => exit(exit_code(run_analyze(&args))),
+        Command::Render(args) => exit(exit_code(run_render(&args))),
+        Command::Inspect(args) => exit(exit_code(run_inspect(&args))),
+        Command::Bench { iterations, args } => exit(exit_code(run_bench(&args, iterations))),
+    };
+
+    if args.frames.is_some() && !matches!(args.format(), OutputFormat::Png | OutputFormat::Gif) {
+        use clap::{error::ErrorKind, CommandFactory};
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "\"--frames\" only works with PNG and GIF",
+            )
+            .exit()
+    }
+
+    if args.video || args.raw_size.is_some() {
+        let t_started = Instant::now();
+        match run_video(&args) {
+            Ok(n) => {
+                let t_used = t_started.elapsed().as_secs_f32();
+                eprintln!(
+                    "\n{} frame(s) processed in {:.2}s, average {:.1} fps.",
+                    n,
+                    t_used,
+                    n as f32 / t_used,
+                );
+                exit(0)
+            }
+            Err(e) => {
+                eprintln!("\nFATAL: {}.", err_cast(e));
+                exit(3)
+            }
+        }
+    }
+
+    if args.watch {
+        if let Err(e) = run_watch(args) {
+            eprintln!("\nFATAL: {}.", err_cast(e));
+        }
+        exit(3)
+    }
+
+    let (sdpairs, is_batch) = match src_dst_pairs(&args) {
+        Ok(ps) => ps,
+        Err(e) => {
+            eprintln!("FATAL: {}.", err_cast(e));
+            exit(3)
+        }
+    };
+
+    let brush = match args.to_brush() {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("FATAL: {}.", err_cast(e));
+            exit(3)
+        }
+    };
+
+    exit(run_batch(&args, brush, sdpairs, is_batch))
+}
+
+/// Process the pairs of images in parallel, and write them in order. Returns the exit code.
+fn run_batch(args: &Args, brush: Arc<dyn Brush>, sdpairs: Pairs, is_batch: bool) -> i32 {
+    use std::{
+        collections::BTreeMap,
+        io::Write,
+        panic,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Mutex,
+        },
+        thread,
+        time::Instant,
+    };
+
+    use threadpool::ThreadPool;
+
+    use src_dst_clarifier::*;
+
+    let num_threads = match is_batch {
+        false => 1,
+        true => match args.parallelism {
+            Some(n) => n,
+            None => num_cpus::get(),
+        },
+    };
+    let buffer_size = match args.buffer_size {
+        Some(n) => n,
+        None => match is_batch {
+            true => 1920 * 1080,
+            false => 7680 * 4320,
+        },
+    };
+
+    let resume = match Resume::new(args) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("FATAL: {}.", err_cast(e.into()));
+            return 3;
+        }
+    };
+    let mut manifest = match &args.manifest {
+        None => None,
+        Some(path) => match || -> io::Result<std::fs::File> {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", Resume::MANIFEST_HEADER)?;
+            }
+            Ok(file)
+        }() {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("FATAL: {}.", err_cast(e.into()));
+                return 3;
+            }
+        },
+    };
+
+    /// An image to decode, on its way to the workers.
+    struct Job {
+        index: usize,
+        /// 在所有图像（包括跳过的）中的序号，决定笔刷的时间
+        nth: usize,
+        src: Src,
+        dst: Dst,
+    }
+
+    /// An encoded image, on its way to be written in order.
+    struct Done {
+        index: usize,
+        src: PathBuf,
+        dst: Dst,
+        result: Result<Output, Box<dyn Error + Send + Sync>>,
+    }
+
+    // 流水线：分发（单独一个线程）→ 读取、解码、分析、渲染并编码（线程池）→ 按顺序写出（主线程）
+    // 每张图从读取到写出都要占一张票，票数限制了内存中的图像数量
+    let tickets = num_threads * 2;
+    let (ticket_tx, ticket_rx) = mpsc::sync_channel::<()>(tickets);
+    (0..tickets).for_each(|_| ticket_tx.send(()).unwrap());
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(num_threads);
+    let (done_tx, done_rx) = mpsc::channel::<Done>();
+
+    let t_started = Instant::now();
+
+    let format = args.format();
+
+    let resume = Arc::new(resume);
+    let skipped = Arc::new(AtomicUsize::new(0));
+    let resuming = resume.skip_existing || !resume.recorded.is_empty();
+    // 跳过已经完成的，之后的序号仍然连续
+    let sdpairs = sdpairs.enumerate().filter({
+        let resume = resume.clone();
+        let skipped = skipped.clone();
+        move |(_, pair)| match pair {
+            (Src::File(src), Dst::File(dst)) if resuming => {
+                let done = resume.is_done(src, &format.output_path(dst.clone()));
+                if done {
+                    skipped.fetch_add(1, Ordering::Relaxed);
+                }
+                !done
+            }
+            _ => true,
+        }
+    });
+
+    let dispatcher = thread::spawn(move || {
+        for (index, (nth, (src, dst))) in sdpairs.enumerate() {
+            if ticket_rx.recv().is_err() {
+                break;
+            }
+            if job_tx
+                .send(Job {
+                    index,
+                    nth,
+                    src,
+                    dst,
+                })
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let thread_pool = ThreadPool::new(num_threads);
+    for _ in 0..num_threads {
+        let job_rx = job_rx.clone();
+        let done_tx = done_tx.clone();
+        let params = args.to_params(&brush);
+        let extras = args.to_extras();
+        let fps = args.framerate;
+        thread_pool.execute(move || {
+            let mut canvas = vec![(0u8, SampleType::zeros()); buffer_size].into_boxed_slice();
+            loop {
+                // 锁只在取任务时持有
+                let job = job_rx.lock().unwrap().recv();
+                let Ok(Job {
+                    index,
+                    nth,
+                    src,
+                    dst,
+                }) = job
+                else {
+                    break;
+                };
+                let path = match &src {
+                    Src::File(p) => p.clone(),
+                    Src::Stdin => PathBuf::from("-"),
+                };
+                // 一张图出了 panic 也要交回结果，否则写出时会一直等它
+                let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    decode(&src).and_then(|img| {
+                        let time_elapsed = nth as f32 / fps;
+                        process(
+                            img,
+                            &path,
+                            &mut canvas,
+                            params.clone(),
+                            extras.clone(),
+                            time_elapsed,
+                        )
+                    })
+                }))
+                .unwrap_or_else(|payload| {
+                    let msg = payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_else(|| "unknown cause".to_string());
+                    Err(format!("panicked: {msg}").into())
+                });
+                let src = path;
+                if done_tx
+                    .send(Done {
+                        index,
+                        src,
+                        dst,
+                        result,
+                    })
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+    drop(done_tx);
+
+    let err_max = args.max_errors;
+    let mut err_ctr = 0usize;
+    let mut succ_ctr = 0usize;
+    let mut milestone = 1usize;
+    const MILESTONE_INTERVAL: usize = 500;
+
+    let mut stderr = io::stderr();
+    let mut exit_code = 0i32;
+
+    let mut reports = Vec::new();
+
+    // 先完成的暂存起来，按顺序写出和报告
+    let mut pending = BTreeMap::<usize, Done>::new();
+    let mut next = 0usize;
+    'writing: for done in done_rx {
+        pending.insert(done.index, done);
+        while let Some(Done {
+            src, dst, result, ..
+        }) = pending.remove(&next)
+        {
+            next += 1;
+            let written = match &dst {
+                Dst::File(p) => Some(format.output_path(p.clone())),
+                Dst::Stdout => None,
+            };
+            let mut report = serde_json::json!({
+                "src": src,
+                "dst": written.as_deref().unwrap_or(Path::new("-")),
+            });
+            match result.and_then(|Output { bytes, info, stats }| {
+                write(dst, format, &bytes)?;
+                // 写完了才记录，中途中断的不算
+                if let (Some(manifest), Some(written)) = (&mut manifest, &written) {
+                    if let Some(line) = resume.record(&src, written) {
+                        manifest.write_all(line.as_bytes())?;
+                        manifest.flush()?;
+                    }
+                }
+                Ok((info, stats))
+            }) {
+                Ok((info, stats)) => {
+                    succ_ctr += 1;
+                    if let (Some(report), Some(serde_json::Value::Object(stats))) =
+                        (report.as_object_mut(), stats)
+                    {
+                        report.extend(stats);
+                    }
+                    eprint!("{info}");
+                    eprint!(".");
+                }
+                Err(e) => {
+                    err_ctr += 1;
+                    exit_code = 1;
+                    let e = err_cast(e);
+                    eprintln!("\nERROR ({err_ctr}/{err_max}): {}: {e}.", src.display());
+                    report["error"] = e.into();
+                }
+            }
+            if args.report.is_some() {
+                reports.push(report);
+            }
+            // 写出一张，才放进来下一张
+            ticket_tx.send(()).ok();
+
+            if succ_ctr > milestone * MILESTONE_INTERVAL {
+                eprintln!(
+                    "\nINFO: {} images have been processed.",
+                    milestone * MILESTONE_INTERVAL
+                );
+                milestone += 1;
+            }
+
+            if err_ctr >= err_max {
+                exit_code = 2;
+                eprintln!("\nFATAL: Too many errors ({err_max}/{err_max}).");
+                break 'writing;
+            }
+
+            stderr.flush().ok();
+        }
+    }
+
+    if exit_code != 2 {
+        dispatcher.join().ok();
+        thread_pool.join();
+    }
+
+    if let Some(path) = &args.report {
+        let report = serde_json::json!({
+            "options": args.options,
+            "images": reports,
+        });
+        let json = serde_json::to_string_pretty(&report).unwrap_or_default();
+        if let Err(e) = std::fs::write(path, json + "\n") {
+            exit_code = exit_code.max(1);
+            eprintln!(
+                "\nERROR: Failed to write the report: {}.",
+                err_cast(e.into())
+            );
+        }
+    }
+
+    let skipped = skipped.load(Ordering::Relaxed);
+    if skipped > 0 {
+        eprintln!("\nINFO: {skipped} image(s) skipped, which have been done.");
+    }
+
+    let t_finished = Instant::now();
+    let t_used = (t_finished - t_started).as_secs_f32();
+    eprintln!(
         "\n{} image(s) processed in {:.2}s, average {:.1} fps.",
         succ_ctr,
         t_used,
         succ_ctr as f32 / t_used,
     );
 
-    exit(exit_code)
+    exit_code
+}
+
+/// Process the images, then process them again whenever they (or the config file) change.
+fn run_watch(mut args: Args) -> Result<(), Box<dyn Error>> {
+    use std::{collections::HashSet, sync::mpsc, time::Duration};
+
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use src_dst_clarifier::Src;
+
+    // 一次保存往往会有好几个事件，等安静下来再处理
+    const QUIET: Duration = Duration::from_millis(200);
+
+    let mut brush = args.to_brush()?;
+    let (sdpairs, is_batch) = src_dst_pairs(&args)?;
+    run_batch(&args, brush.clone(), sdpairs, is_batch);
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    // 编辑器保存时常常是替换掉原来的文件，所以监视文件所在的目录
+    let src = std::fs::canonicalize(&args.src)?;
+    match (src.is_dir(), src.parent()) {
+        (true, _) | (false, None) => watcher.watch(
+            &src,
+            match args.recursive {
+                true => RecursiveMode::Recursive,
+                false => RecursiveMode::NonRecursive,
+            },
+        )?,
+        (false, Some(parent)) => watcher.watch(parent, RecursiveMode::NonRecursive)?,
+    }
+    let config = args
+        .config
+        .as_deref()
+        .map(std::fs::canonicalize)
+        .transpose()?;
+    if let Some(parent) = config.as_deref().and_then(Path::parent) {
+        watcher.watch(parent, RecursiveMode::NonRecursive)?;
+    }
+
+    let watching = || eprintln!("\nINFO: Watching {src:?} for changes...");
+    watching();
+    loop {
+        let mut changed = HashSet::new();
+        let mut event = rx.recv()?;
+        loop {
+            match event {
+                Ok(e) if matches!(e.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                    changed.extend(e.paths)
+                }
+                Ok(_) => (),
+                Err(e) => eprintln!("\nERROR: {}.", err_cast(e.into())),
+            }
+            match rx.recv_timeout(QUIET) {
+                Ok(e) => event = e,
+                Err(_) => break,
+            }
+        }
+
+        let reload = config.as_ref().is_some_and(|c| changed.contains(c));
+        if reload {
+            let reloaded = match Cli::try_load().map(|c| c.args().clone()) {
+                Ok(a) => a.to_brush().map(|b| (b, a)).map_err(err_cast),
+                // 只要第一行，不要用法
+                Err(e) => Err(e
+                    .to_string()
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .trim_start_matches("error: ")
+                    .into()),
+            };
+            match reloaded {
+                Ok((b, a)) => {
+                    eprintln!("\nINFO: Options reloaded.");
+                    (brush, args) = (b, a);
+                }
+                Err(e) => {
+                    eprintln!("\nERROR: Failed to reload options: {e}.");
+                    watching();
+                    continue;
+                }
+            }
+        }
+
+        let (sdpairs, is_batch) = match src_dst_pairs(&args) {
+            Ok(ps) => ps,
+            Err(e) => {
+                eprintln!("\nERROR: {}.", err_cast(e));
+                watching();
+                continue;
+            }
+        };
+        let sdpairs = sdpairs
+            .filter(|(s, _)| reload || matches!(s, Src::File(p) if changed.contains(p)))
+            .collect::<Vec<_>>();
+        if !sdpairs.is_empty() {
+            run_batch(
+                &args,
+                brush.clone(),
+                Box::new(sdpairs.into_iter()),
+                is_batch,
+            );
+            watching();
+        }
+    }
+}
+
+/// Where to read a quadtree file or an image from, where "-" is stdin.
+fn read_src(src: &Path) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    match src.as_os_str() == "-" {
+        true => {
+            let mut buf = Vec::new();
+            io::stdin().lock().read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        false => std::fs::read(src),
+    }
+}
+
+/// Analyze the image, and save the quadtree to a file.
+fn run_analyze(args: &Args) -> Result<(), Box<dyn Error>> {
+    use std::io::Write;
+
+    let img = image::load_from_memory(&read_src(&args.src)?)?.into_rgba8();
+    let (ge_params, an_params, re_params, _) = args.to_params(&args.to_brush()?);
+    let extras = Extras {
+        output_size: None,
+        metrics: false,
+        report: false,
+        ..args.to_extras()
+    };
+
+    let (w, h) = img.dimensions();
+    let mut canvas = vec![(0u8, SampleType::zeros()); w as usize * h as usize];
+    let analyzed = Analyzed::new(img, &mut canvas, (ge_params, an_params, re_params), &extras)
+        .map_err(|e| e as Box<dyn Error>)?;
+
+    let mut bytes = Vec::new();
+    save_quadtree(&mut bytes, analyzed.canvas, analyzed.size, ge_params)?;
+    let dst = match (&args.dst, args.src.as_os_str() == "-") {
+        (Some(dst), _) => dst.clone(),
+        (None, true) => PathBuf::from("-"),
+        (None, false) => args.src.with_extension("qdt"),
+    };
+    match dst.as_os_str() == "-" {
+        true => io::stdout().lock().write_all(&bytes)?,
+        false => std::fs::write(&dst, &bytes)?,
+    }
+
+    let counts = depth_histogram(analyzed.canvas, analyzed.size, ge_params);
+    if args.histogram {
+        eprint!(
+            "INFO: Nodes at each depth of {:?}:\n{}",
+            args.src,
+            histogram_bars(&counts)
+        );
+    }
+    if extras.target.is_some() {
+        let AnalyzeParams {
+            thres_ay,
+            thres_cbcr,
+            ..
+        } = analyzed.an_params;
+        eprintln!("INFO: Thresholds: -Y {thres_ay:.3} -C {thres_cbcr:.3}");
+        if let Some(reached) = analyzed.missed_psnr {
+            eprintln!("INFO: The target PSNR is out of reach, {reached:.2} dB instead.");
+        }
+    }
+    let (w, h) = analyzed.size;
+    eprintln!(
+        "INFO: {} nodes ({w}x{h}) saved to {dst:?}.",
+        counts.iter().sum::<usize>()
+    );
+    Ok(())
+}
+
+/// The canvas to load a quadtree file into, as large as the image in its header, which must fit in "--buffer".
+fn quadtree_canvas(
+    args: &Args,
+    header: &QuadtreeHeader,
+) -> Result<Vec<CanvasPixel>, Box<dyn Error>> {
+    // 文件头里的尺寸不可信，不能直接按它分配
+    let (w, h) = header.img_size;
+    let pixels = w as u64 * h as u64;
+    if pixels > args.buffer_size.unwrap_or(7680 * 4320) as u64 {
+        return Err(QuadtreeError::ImageTooLarge.into());
+    }
+    Ok(vec![(0u8, SampleType::zeros()); pixels as usize])
+}
+
+/// Render a quadtree file from `analyze`.
+fn run_render(args: &Args) -> Result<(), Box<dyn Error>> {
+    use src_dst_clarifier::Dst;
+
+    let bytes = read_src(&args.src)?;
+    let mut reader = bytes.as_slice();
+    let header = QuadtreeHeader::read(&mut reader)?;
+    let mut canvas = quadtree_canvas(args, &header)?;
+    load_quadtree(&mut reader, &header, &mut canvas)?;
+
+    let brush = args.to_brush()?;
+    let (_, an_params, re_params, brush) = args.to_params(&brush);
+    let extras = Extras {
+        metrics: false,
+        report: false,
+        target: None,
+        ..args.to_extras()
+    };
+    let out_size = extras
+        .output_size
+        .map_or(header.img_size, |s| s.resolve(header.img_size));
+    // 文件里没有原图，只能从空白开始
+    let analyzed = Analyzed {
+        canvas: &canvas,
+        size: header.img_size,
+        base: ImageType::new(out_size.0, out_size.1),
+        source: None,
+        flat: None,
+        an_params,
+        missed_psnr: None,
+    };
+
+    let format = extras.format;
+    let output = encode(
+        analyzed,
+        &args.src,
+        (header.ge_params, an_params, re_params, brush),
+        extras,
+        0.,
+        std::time::Duration::ZERO,
+    )
+    .map_err(|e| e as Box<dyn Error>)?;
+    let dst = match (&args.dst, args.src.as_os_str() == "-") {
+        (Some(dst), _) if dst.as_os_str() == "-" => Dst::Stdout,
+        (Some(dst), _) => Dst::File(dst.clone()),
+        (None, true) => Dst::Stdout,
+        // 不能覆盖分析的那张图，例如 "photo.qdt" 输出到 "photo.render.png"，而不是 "photo.png"
+        (None, false) => Dst::File(args.src.with_extension("render.qdt")),
+    };
+    write(dst, format, &output.bytes)?;
+    eprint!("{}", output.info);
+    Ok(())
+}
+
+/// Print the statistics of the quadtree of an image or a quadtree file.
+fn run_inspect(args: &Args) -> Result<(), Box<dyn Error>> {
+    use std::collections::HashSet;
+
+    let bytes = read_src(&args.src)?;
+    let mut reader = bytes.as_slice();
+    let mut canvas;
+    // 不是四叉树文件的话，就当作图像来分析
+    let (size, ge_params, analyzed) = match QuadtreeHeader::read(&mut reader) {
+        Ok(header) => {
+            canvas = quadtree_canvas(args, &header)?;
+            load_quadtree(&mut reader, &header, &mut canvas)?;
+            (header.img_size, header.ge_params, None)
+        }
+        Err(QuadtreeError::NotQuadtree) => {
+            let img = image::load_from_memory(&bytes)?.into_rgba8();
+            let (ge_params, an_params, re_params, _) = args.to_params(&args.to_brush()?);
+            let extras = Extras {
+                output_size: None,
+                metrics: true,
+                report: false,
+                ..args.to_extras()
+            };
+            let (w, h) = img.dimensions();
+            canvas = vec![(0u8, SampleType::zeros()); w as usize * h as usize];
+            let analyzed =
+                Analyzed::new(img, &mut canvas, (ge_params, an_params, re_params), &extras)
+                    .map_err(|e| e as Box<dyn Error>)?;
+            (
+                analyzed.size,
+                ge_params,
+                Some((analyzed.an_params, analyzed.flat)),
+            )
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut colors = HashSet::new();
+    let (mut smallest, mut largest) = (u64::MAX, 0u64);
+    for_each_leaf(&canvas, size, ge_params, |leaf| {
+        let area = leaf.area_size.0 as u64 * leaf.area_size.1 as u64;
+        if area > 0 {
+            colors.insert(leaf.color);
+            smallest = smallest.min(area);
+            largest = largest.max(area);
+        }
+    });
+    let counts = depth_histogram(&canvas, size, ge_params);
+    let leaves = counts.iter().sum::<usize>();
+    let deepest = counts.iter().rposition(|&n| n > 0).map_or(0, |d| d + 1);
+
+    let GenericParams {
+        slicing_ratio: (ratio_w, ratio_h),
+        max_depth,
+        gradient,
+        ..
+    } = ge_params;
+    println!("Size:       {}x{}", size.0, size.1);
+    println!("Ratio:      {ratio_w}:{ratio_h}");
+    println!("Depth:      {deepest} (at most {max_depth})");
+    println!("Gradient:   {}", if gradient { "yes" } else { "no" });
+    println!("Nodes:      {leaves}");
+    println!("Colors:     {}", colors.len());
+    if leaves > 0 {
+        println!(
+            "Node area:  {smallest} to {largest} pixels, {:.1} on average",
+            size.0 as f64 * size.1 as f64 / leaves as f64
+        );
+    }
+    if let Some((an_params, flat)) = analyzed {
+        println!(
+            "Thresholds: -Y {:.3} -C {:.3}",
+            an_params.thres_ay, an_params.thres_cbcr
+        );
+        if let Some(q) = flat {
+            println!(
+                "Error:      MSE {:.2} | PSNR {:.2} dB | SSIM {:.4}",
+                q.mse, q.psnr, q.ssim
+            );
+        }
+    }
+    print!("Nodes at each depth:\n{}", histogram_bars(&counts));
+    Ok(())
+}
+
+/// Decode, analyze, render and encode the image over and over, and print how long each stage takes,
+/// then compare the merge methods and the brushes.
+fn run_bench(args: &Args, iterations: u32) -> Result<(), Box<dyn Error>> {
+    use std::time::{Duration, Instant};
+
+    use clap::ValueEnum;
+
+    /// The time taken by each iteration.
+    struct Timings(Vec<Duration>);
+
+    impl Timings {
+        /// Nearest rank, so that p99 of fewer than 100 iterations is the slowest one.
+        fn quantile(&self, q: f64) -> Duration {
+            let mut sorted = self.0.clone();
+            sorted.sort_unstable();
+            let rank = (q * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        }
+
+        fn row(&self, name: &str, leaves: usize) -> String {
+            let ms = |t: Duration| t.as_secs_f64() * 1000.;
+            let median = self.quantile(0.5).as_secs_f64().max(f64::MIN_POSITIVE);
+            format!(
+                "{name:>12} | {leaves:>8} | {:>9.3} | {:>9.3} | {:>9.3} | {:>9.1} | {:>12.0}",
+                ms(self.quantile(0.)),
+                ms(self.quantile(0.5)),
+                ms(self.quantile(0.99)),
+                1. / median,
+                leaves as f64 / median,
+            )
+        }
+    }
+
+    /// The name on the command line.
+    fn name_of(value: impl ValueEnum) -> String {
+        value
+            .to_possible_value()
+            .map_or_else(String::new, |v| v.get_name().to_owned())
+    }
+
+    /// Time `f` only, with what `prepare` prepares for it each time.
+    fn measure<T>(
+        iterations: u32,
+        mut prepare: impl FnMut() -> T,
+        mut f: impl FnMut(u32, T) -> Result<(), Box<dyn Error>>,
+    ) -> Result<Timings, Box<dyn Error>> {
+        let mut timings = Vec::with_capacity(iterations as usize);
+        for i in 0..iterations {
+            let prepared = prepare();
+            let t_started = Instant::now();
+            f(i, prepared)?;
+            timings.push(t_started.elapsed());
+        }
+        Ok(Timings(timings))
+    }
+
+    fn analyze<'a>(
+        img: ImageType,
+        canvas: &'a mut [CanvasPixel],
+        params: (GenericParams, AnalyzeParams, RenderParams),
+        extras: &Extras,
+    ) -> Result<Analyzed<'a>, Box<dyn Error>> {
+        Analyzed::new(img, canvas, params, extras).map_err(|e| e as Box<dyn Error>)
+    }
+
+    let header = format!(
+        "{:>12} | {:>8} | {:>9} | {:>9} | {:>9} | {:>9} | {:>12}",
+        "", "nodes", "min (ms)", "median", "p99", "fps", "nodes/s"
+    );
+
+    // 从内存里解码，不算读文件的时间
+    let bytes = read_src(&args.src)?;
+    let decode = || -> Result<ImageType, Box<dyn Error>> {
+        Ok(image::load_from_memory(&bytes)?.into_rgba8())
+    };
+    let img = decode()?;
+    let (w, h) = img.dimensions();
+
+    let brush = args.to_brush()?;
+    let (ge_params, an_params, re_params, brush) = args.to_params(&brush);
+    let extras = Extras {
+        metrics: false,
+        report: false,
+        ..args.to_extras()
+    };
+    let time_at = |i: u32| i as f32 / extras.framerate;
+    let mut canvas = vec![(0u8, SampleType::zeros()); w as usize * h as usize];
+
+    /* ----- 每个阶段 ----- */
+    let mut stages: [Vec<Duration>; 4] = Default::default();
+    let mut leaves = 0;
+    for i in 0..iterations {
+        let mut lap = {
+            let mut t_started = Instant::now();
+            move |stage: &mut Vec<Duration>| {
+                stage.push(t_started.elapsed());
+                t_started = Instant::now();
+            }
+        };
+        let img = decode()?;
+        lap(&mut stages[0]);
+        let mut analyzed = analyze(img, &mut canvas, (ge_params, an_params, re_params), &extras)?;
+        lap(&mut stages[1]);
+        let base = std::mem::take(&mut analyzed.base);
+        let frame = analyzed.render(base, &brush, ge_params, re_params, extras.grid, time_at(i))?;
+        lap(&mut stages[2]);
+        let mut encoded = Vec::new();
+        match extras.format {
+            OutputFormat::Text | OutputFormat::Ansi => {
+                encoded = render_text(analyzed.canvas, analyzed.size, ge_params, extras.te_params)
+                    .into_bytes()
+            }
+            format => format
+                .encode(&frame, extras.quality, extras.png_compression, &mut encoded)
+                .map_err(|e| e as Box<dyn Error>)?,
+        }
+        lap(&mut stages[3]);
+
+        leaves = depth_histogram(analyzed.canvas, analyzed.size, ge_params)
+            .iter()
+            .sum::<usize>();
+    }
+    let total = Timings(
+        (0..iterations as usize)
+            .map(|i| stages.iter().map(|s| s[i]).sum())
+            .collect(),
+    );
+
+    println!(
+        "{:?}: {w}x{h}, {leaves} nodes, {iterations} iteration(s)\n",
+        args.src
+    );
+    println!("{header}");
+    let names = ["decode", "analyze", "render", "encode"];
+    for (name, stage) in names.into_iter().zip(stages) {
+        println!("{}", Timings(stage).row(name, leaves));
+    }
+    println!("{}", total.row("total", leaves));
+
+    /* ----- 比较合并方法 ----- */
+    println!("\nAnalyze with \"--merge\":");
+    println!("{header}");
+    for &merge_method in MergeMethod::value_variants() {
+        let an_params = AnalyzeParams {
+            merge_method,
+            ..an_params
+        };
+        let analyzed = analyze(
+            img.clone(),
+            &mut canvas,
+            (ge_params, an_params, re_params),
+            &extras,
+        )?;
+        let leaves = depth_histogram(analyzed.canvas, analyzed.size, ge_params)
+            .iter()
+            .sum();
+        let timings = measure(
+            iterations,
+            || img.clone(),
+            |_, img| {
+                analyze(img, &mut canvas, (ge_params, an_params, re_params), &extras).map(drop)
+            },
+        )?;
+        let name = name_of(merge_method);
+        let mark = match merge_method == args.merge_method {
+            true => "*",
+            false => " ",
+        };
+        println!("{}", timings.row(&format!("{mark}{name}"), leaves));
+    }
+
+    /* ----- 比较笔刷 ----- */
+    println!("\nRender with \"--shape\":");
+    println!("{header}");
+    for &classic in ClassicBrush::value_variants() {
+        let brush: Arc<dyn Brush> = Arc::new(classic);
+        let ge_params = ge_params.with_gradient(classic == ClassicBrush::Smooth);
+        let analyzed = analyze(
+            img.clone(),
+            &mut canvas,
+            (ge_params, an_params, re_params),
+            &extras,
+        )?;
+        let leaves = depth_histogram(analyzed.canvas, analyzed.size, ge_params)
+            .iter()
+            .sum();
+        let timings = measure(
+            iterations,
+            || analyzed.base.clone(),
+            |i, base| {
+                analyzed.render(base, &brush, ge_params, re_params, extras.grid, time_at(i))?;
+                Ok(())
+            },
+        )?;
+        let name = name_of(classic);
+        let mark = match classic == args.brush
+            && !args.debug_depth
+            && args.sprites.is_empty()
+            && args.brush_script.is_none()
+        {
+            true => "*",
+            false => " ",
+        };
+        println!("{}", timings.row(&format!("{mark}{name}"), leaves));
+    }
+
+    Ok(())
+}
+
+/// The pairs of images to process, and whether it is a batch.
+fn src_dst_pairs(args: &Args) -> Result<(Pairs, bool), Box<dyn Error>> {
+    use src_dst_clarifier::*;
+
+    let include = args.include()?;
+    if args.recursive {
+        let pairs = walk_tree(&args.src, args.dst.as_deref(), include.as_ref())?;
+        return Ok((Box::new(pairs.into_iter()), true));
+    }
+
+    let ps = SrcDstConfig {
+        allow_from_stdin: true,
+        allow_to_stdout: true,
+        auto_tnamed_dst_file: true,
+        auto_tnamed_dst_dir: true,
+        default_extension: args.format().extension().into(),
+        allow_inplace: false,
+    }
+    .parse(&args.src, args.dst.as_ref())??;
+    ps.create_tnamed_dir()?;
+    let is_batch = ps.is_batch();
+    Ok(match include {
+        Some(include) if is_batch => (
+            Box::new(ps.filter(move |(src, _)| match src {
+                Src::File(p) => p.file_name().is_some_and(|n| include.is_match(n)),
+                Src::Stdin => true,
+            })),
+            true,
+        ),
+        _ => (Box::new(ps), is_batch),
+    })
+}
+
+/// Pair every matching file under `src` with the same relative path under `dst` (or a time-based named
+/// directory beside `src`), creating the directories on the way.
+fn walk_tree(
+    src: &std::path::Path,
+    dst: Option<&std::path::Path>,
+    include: Option<&globset::GlobSet>,
+) -> Result<Vec<(src_dst_clarifier::Src, src_dst_clarifier::Dst)>, Box<dyn Error>> {
+    use std::{fs, io};
+
+    use src_dst_clarifier::{Dst, Src, SrcDstError};
+
+    if !src.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"--recursive\" needs a directory, but {src:?} is not"),
+        )
+        .into());
+    }
+    let src = fs::canonicalize(src)?;
+
+    let dst = match dst {
+        Some(dst) => {
+            fs::create_dir_all(dst)?;
+            fs::canonicalize(dst)?
+        }
+        None => {
+            // ./inputs => ./inputs-A01123-0456-0789，和非递归时一样
+            let dst = src.with_file_name(format!(
+                "{}-{}",
+                src.file_name().unwrap_or_default().to_string_lossy(),
+                kalavor::Katetime::now_datetime()
+            ));
+            fs::create_dir(&dst)?;
+            dst
+        }
+    };
+    if dst == src {
+        return Err(SrcDstError::Inplaced.into());
+    }
+
+    let mut pairs = Vec::new();
+    // DST 在 SRC 里面的话，不要把之前的输出也当成输入
+    let walker = walkdir::WalkDir::new(&src)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|e| e.path() != dst);
+    for entry in walker {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.into_path();
+        let relative = path.strip_prefix(&src)?.to_owned();
+        let matched = match include {
+            Some(include) => include.is_match(&relative),
+            None => image::ImageFormat::from_path(&path).is_ok(),
+        };
+        if matched {
+            let out = dst.join(&relative);
+            if let Some(parent) = out.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            pairs.push((Src::File(path), Dst::File(out)));
+        }
+    }
+
+    Ok(pairs)
+}
+
+/// Stylize a video frame by frame, decoded by `ffmpeg` ("--video") or from raw frames on stdin ("--raw-size").
+///
+/// Returns the number of frames written.
+fn run_video(args: &Args) -> Result<usize, Box<dyn Error>> {
+    use std::{
+        collections::BTreeMap,
+        io::{self, BufWriter, Read, Write},
+        process::{Child, Command, Stdio},
+        sync::mpsc,
+    };
+
+    use object_pool::Pool;
+    use threadpool::ThreadPool;
+
+    type FrameResult = Result<ImageType, Box<dyn Error + Send + Sync>>;
+
+    /// 读满一帧，在帧与帧之间遇到 EOF 则返回 `false`
+    fn read_frame(input: &mut dyn Read, buf: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match input.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn video_worker(
+        tx: mpsc::Sender<(usize, FrameResult)>,
+        (index, img): (usize, ImageType),
+        canvas_pool: Arc<Pool<Box<[CanvasPixel]>>>,
+        (ge_params, an_params, re_params, brush): (
+            GenericParams,
+            AnalyzeParams,
+            RenderParams,
+            Arc<dyn Brush>,
+        ),
+        extras: Extras,
+        time_elapsed: f32,
+    ) {
+        let result = (|| {
+            let mut canvas = canvas_pool.try_pull().unwrap();
+            let mut analyzed =
+                Analyzed::new(img, &mut canvas, (ge_params, an_params, re_params), &extras)?;
+            let base = std::mem::take(&mut analyzed.base);
+            Ok(analyzed.render(
+                base,
+                &brush,
+                ge_params,
+                re_params,
+                extras.grid,
+                time_elapsed,
+            )?)
+        })();
+        tx.send((index, result)).unwrap();
+    }
+
+    /// 出错提前返回时，不要留下还在运行的 `ffmpeg`
+    struct Children(Vec<Child>);
+
+    impl Drop for Children {
+        fn drop(&mut self) {
+            for child in &mut self.0 {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    /// `ffprobe` 给出的帧率是分数，例如 "30000/1001"
+    fn parse_rate(rate: &str) -> Option<f32> {
+        let (num, den) = rate.split_once('/').unwrap_or((rate, "1"));
+        let rate = num.trim().parse::<f32>().ok()? / den.trim().parse::<f32>().ok()?;
+        (rate.is_finite() && rate > 0.).then_some(rate)
+    }
+
+    let brush = args.to_brush()?;
+
+    let mut children = Children(Vec::new());
+    let ((size, framerate), mut input, mut output): (_, Box<dyn Read>, Box<dyn Write>) =
+        match args.raw_size {
+            Some(size) => {
+                if args.src.as_os_str() != "-" {
+                    return Err("IMAGE must be \"-\" with \"--raw-size\"".into());
+                }
+                (
+                    (size, args.framerate),
+                    Box::new(io::stdin().lock()),
+                    Box::new(BufWriter::new(io::stdout().lock())),
+                )
+            }
+            None => {
+                let probe = Command::new("ffprobe")
+                    .args(["-v", "error", "-select_streams", "v:0"])
+                    .args(["-show_entries", "stream=width,height,r_frame_rate"])
+                    .args(["-of", "csv=p=0"])
+                    .arg(&args.src)
+                    .output()
+                    .map_err(|e| format!("cannot run `ffprobe`: {e}"))?;
+                let probed = String::from_utf8_lossy(&probe.stdout);
+                let mut fields = probed.trim().split(',');
+                let (Some(w), Some(h), Some(rate)) = (
+                    fields.next().and_then(|w| w.parse::<u32>().ok()),
+                    fields.next().and_then(|h| h.parse::<u32>().ok()),
+                    fields.next(),
+                ) else {
+                    return Err(format!(
+                        "cannot find a video stream in {:?}: {}",
+                        args.src,
+                        String::from_utf8_lossy(&probe.stderr).trim()
+                    )
+                    .into());
+                };
+                let out_size = args.output_size.map_or((w, h), |s| s.resolve((w, h)));
+                let framerate = parse_rate(rate).unwrap_or(args.framerate);
+
+                let mut decoder = Command::new("ffmpeg")
+                    .args(["-v", "error", "-i"])
+                    .arg(&args.src)
+                    .args(["-f", "rawvideo", "-pix_fmt", "rgba", "-"])
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("cannot run `ffmpeg`: {e}"))?;
+                let input = Box::new(decoder.stdout.take().unwrap());
+                children.0.push(decoder);
+                let mut encoder = Command::new("ffmpeg")
+                    .args(["-v", "error", "-y", "-f", "rawvideo", "-pix_fmt", "rgba"])
+                    .args(["-s", &format!("{}x{}", out_size.0, out_size.1)])
+                    .args(["-framerate", rate, "-i", "-", "-i"])
+                    .arg(&args.src)
+                    .args(["-map", "0:v", "-map", "1:a?", "-c:a", "copy"])
+                    .arg(args.dst.as_ref().unwrap())
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .map_err(|e| format!("cannot run `ffmpeg`: {e}"))?;
+
+                let output = Box::new(BufWriter::new(encoder.stdin.take().unwrap()));
+                children.0.push(encoder);
+                (((w, h), framerate), input, output)
+            }
+        };
+
+    let num_threads = match args.parallelism {
+        Some(n) => n,
+        None => num_cpus::get(),
+    };
+    let analyzed_size = args.analyze_size.map_or(size, |s| s.resolve(size));
+    let buffer_size = match args.buffer_size {
+        Some(n) => n,
+        None => analyzed_size.0 as usize * analyzed_size.1 as usize,
+    };
+
+    let thread_pool = ThreadPool::new(num_threads);
+    let canvas_pool = Arc::new(Pool::<Box<[CanvasPixel]>>::new(num_threads, || {
+        vec![(0u8, SampleType::zeros()); buffer_size].into_boxed_slice()
+    }));
+    let (tx, rx) = mpsc::channel::<(usize, FrameResult)>();
+
+    // 先完成的帧暂存起来，按顺序写出
+    let mut pending = BTreeMap::<usize, ImageType>::new();
+    let (mut read, mut written) = (0usize, 0usize);
+    let mut eof = false;
+
+    loop {
+        // 同时在处理的帧不要太多
+        while !eof && read - written < num_threads * 2 {
+            let mut buf = vec![0u8; size.0 as usize * size.1 as usize * 4];
+            if !read_frame(&mut input, &mut buf)? {
+                eof = true;
+                break;
+            }
+            let img = ImageType::from_raw(size.0, size.1, buf).unwrap();
+
+            let tx = tx.clone();
+            let canvas_pool = canvas_pool.clone();
+            let params = args.to_params(&brush);
+            let extras = args.to_extras();
+            let time_elapsed = read as f32 / framerate;
+            let index = read;
+            thread_pool.execute(move || {
+                video_worker(tx, (index, img), canvas_pool, params, extras, time_elapsed)
+            });
+            read += 1;
+        }
+
+        if written == read {
+            break;
+        }
+
+        let (index, frame) = rx.recv()?;
+        pending.insert(index, frame.map_err(|e| format!("frame #{index}: {e}"))?);
+        while let Some(frame) = pending.remove(&written) {
+            output.write_all(frame.as_raw())?;
+            written += 1;
+        }
+    }
+
+    output.flush()?;
+    drop(output);
+    // 已经结束的进程，之后 drop 时的 kill 不会有影响
+    for child in &mut children.0 {
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(format!("`ffmpeg` exited with {status}").into());
+        }
+    }
+
+    Ok(written)
 }
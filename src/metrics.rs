@@ -0,0 +1,265 @@
+use super::*;
+
+/// How close an image is to a reference image, see [`compare()`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Mean squared error of the RGB channels, in `0.0..=65025.0`.
+    pub mse: f64,
+    /// Peak signal-to-noise ratio in dB, from [`Quality::mse`]. Infinite for identical images.
+    pub psnr: f64,
+    /// Mean structural similarity of the luma, in `-1.0..=1.0`, where 1 means identical.
+    pub ssim: f64,
+}
+
+#[non_exhaustive]
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricsError {
+    #[error("the sizes of images are different: {0:?} and {1:?}")]
+    SizeMismatch((u32, u32), (u32, u32)),
+    #[error("the image is empty")]
+    Empty,
+}
+
+/// Measure how close the image is to the reference. The alpha channel is ignored.
+pub fn compare(img: &ImageType, reference: &ImageType) -> Result<Quality, MetricsError> {
+    let mse = mse(img, reference)?;
+    Ok(Quality {
+        mse,
+        psnr: psnr_from_mse(mse),
+        ssim: ssim(img, reference)?,
+    })
+}
+
+/// Mean squared error of the RGB channels.
+pub fn mse(img: &ImageType, reference: &ImageType) -> Result<f64, MetricsError> {
+    check_size(img, reference)?;
+    let sum = img
+        .pixels()
+        .zip(reference.pixels())
+        .map(|(a, b)| {
+            (0..3)
+                .map(|i| (a[i] as i64 - b[i] as i64).pow(2))
+                .sum::<i64>()
+        })
+        .sum::<i64>();
+    Ok(sum as f64 / (img.width() as f64 * img.height() as f64 * 3.))
+}
+
+/// Peak signal-to-noise ratio in dB.
+pub fn psnr(img: &ImageType, reference: &ImageType) -> Result<f64, MetricsError> {
+    mse(img, reference).map(psnr_from_mse)
+}
+
+pub fn psnr_from_mse(mse: f64) -> f64 {
+    match mse {
+        0. => f64::INFINITY,
+        mse => 10. * (255f64.powi(2) / mse).log10(),
+    }
+}
+
+/// Mean structural similarity of the luma, over 8×8 windows (every 4 pixels).
+pub fn ssim(img: &ImageType, reference: &ImageType) -> Result<f64, MetricsError> {
+    check_size(img, reference)?;
+
+    const WINDOW: u32 = 8;
+    const STEP: usize = 4;
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+    let (w, h) = img.dimensions();
+    let luma = |img: &ImageType| {
+        img.pixels()
+            .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (luma(img), luma(reference));
+
+    // 图像比窗口还小的话，整张图就是一个窗口
+    let (ww, wh) = (WINDOW.min(w), WINDOW.min(h));
+    let n = (ww * wh) as f64;
+    let mut total = 0.;
+    let mut count = 0usize;
+    for sy in (0..=h - wh).step_by(STEP) {
+        for sx in (0..=w - ww).step_by(STEP) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0., 0., 0., 0., 0.);
+            for y in sy..sy + wh {
+                for x in sx..sx + ww {
+                    let i = util::pos(w, x, y);
+                    sa += a[i];
+                    sb += b[i];
+                    saa += a[i] * a[i];
+                    sbb += b[i] * b[i];
+                    sab += a[i] * b[i];
+                }
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb, cov) = (saa / n - ma * ma, sbb / n - mb * mb, sab / n - ma * mb);
+            total += ((2. * ma * mb + C1) * (2. * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            count += 1;
+        }
+    }
+
+    Ok(total / count as f64)
+}
+
+/// Measure how close the leaves in the canvas from [`analyze()`] are to the image, where each leaf is
+/// filled with its (average) color. This is what the brush starts from, regardless of the brush.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn compare_canvas(
+    img: &ImageType,
+    canvas: CanvasView,
+    ge_params: GenericParams,
+) -> Result<Quality, MetricsError> {
+    compare(&flatten(canvas, img.dimensions(), ge_params), img)
+}
+
+/// Mean squared error of the RGB channels, between the leaves in the canvas from [`analyze()`] and the image.
+///
+/// Cheaper than [`compare_canvas()`], as nothing is allocated.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn canvas_mse(
+    img: &ImageType,
+    canvas: CanvasView,
+    ge_params: GenericParams,
+) -> Result<f64, MetricsError> {
+    check_size(img, img)?;
+    let mut sum = 0i64;
+    for_each_leaf(canvas, img.dimensions(), ge_params, |leaf| {
+        let ((sx, sy), (w, h)) = (leaf.start_at, leaf.area_size);
+        for y in sy..sy + h {
+            for x in sx..sx + w {
+                let p = img.get_pixel(x, y);
+                sum += (0..3)
+                    .map(|i| (p[i] as i64 - leaf.color[i] as i64).pow(2))
+                    .sum::<i64>();
+            }
+        }
+    });
+    Ok(sum as f64 / (img.width() as f64 * img.height() as f64 * 3.))
+}
+
+/// Peak signal-to-noise ratio in dB, between the leaves in the canvas from [`analyze()`] and the image.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn canvas_psnr(
+    img: &ImageType,
+    canvas: CanvasView,
+    ge_params: GenericParams,
+) -> Result<f64, MetricsError> {
+    canvas_mse(img, canvas, ge_params).map(psnr_from_mse)
+}
+
+/// Mean structural similarity of the luma, between the leaves in the canvas from [`analyze()`] and the image.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn canvas_ssim(
+    img: &ImageType,
+    canvas: CanvasView,
+    ge_params: GenericParams,
+) -> Result<f64, MetricsError> {
+    ssim(&flatten(canvas, img.dimensions(), ge_params), img)
+}
+
+/// 每个叶子填满自己的颜色
+fn flatten(canvas: CanvasView, img_size: (u32, u32), ge_params: GenericParams) -> ImageType {
+    let mut flat = ImageType::new(img_size.0, img_size.1);
+    for_each_leaf(canvas, img_size, ge_params, |leaf| {
+        let ((sx, sy), (w, h)) = (leaf.start_at, leaf.area_size);
+        for y in sy..sy + h {
+            for x in sx..sx + w {
+                flat.put_pixel(x, y, leaf.color);
+            }
+        }
+    });
+    flat
+}
+
+fn check_size(img: &ImageType, reference: &ImageType) -> Result<(), MetricsError> {
+    match (img.dimensions(), reference.dimensions()) {
+        (a, b) if a != b => Err(MetricsError::SizeMismatch(a, b)),
+        ((0, _) | (_, 0), _) => Err(MetricsError::Empty),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(size: (u32, u32)) -> ImageType {
+        ImageType::from_fn(size.0, size.1, |x, y| {
+            PixelType::from([
+                (x * 7 % 256) as u8,
+                (y * 11 % 256) as u8,
+                ((x ^ y) * 3) as u8,
+                255,
+            ])
+        })
+    }
+
+    #[test]
+    fn from_mse() {
+        assert_eq!(psnr_from_mse(0.), f64::INFINITY);
+        assert_eq!(psnr_from_mse(255. * 255.), 0.);
+        assert!((psnr_from_mse(255. * 255. / 1e4) - 40.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn identical() {
+        // 比窗口还小的图像也一样
+        for size in [(40, 30), (5, 3)] {
+            let img = image(size);
+            let q = compare(&img, &img.clone()).unwrap();
+            assert_eq!((q.mse, q.psnr), (0., f64::INFINITY));
+            assert!((q.ssim - 1.).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn different() {
+        let img = image((40, 30));
+        let mut darker = img.clone();
+        darker
+            .pixels_mut()
+            .for_each(|p| p[0] = p[0].saturating_sub(30));
+        let q = compare(&darker, &img).unwrap();
+        assert!(q.mse > 0. && q.psnr.is_finite() && q.ssim < 1.);
+
+        assert_eq!(
+            compare(&image((4, 3)), &img),
+            Err(MetricsError::SizeMismatch((4, 3), (40, 30)))
+        );
+        assert_eq!(
+            compare(&image((0, 3)), &image((0, 3))),
+            Err(MetricsError::Empty)
+        );
+    }
+
+    #[test]
+    fn canvas() {
+        let img = image((40, 30));
+        let ge_params = GenericParams::new((4, 3), 4);
+        let an_params = AnalyzeParams {
+            thres_ay: 4.,
+            thres_cbcr: 0.4,
+            merge_method: MergeMethod::StDev,
+        };
+        let mut canvas = vec![(0u8, SampleType::zeros()); 40 * 30];
+        analyze(&img, &mut canvas, ge_params, an_params).unwrap();
+
+        let q = compare_canvas(&img, &canvas, ge_params).unwrap();
+        assert!((canvas_mse(&img, &canvas, ge_params).unwrap() - q.mse).abs() < 1e-9);
+        assert_eq!(canvas_ssim(&img, &canvas, ge_params).unwrap(), q.ssim);
+    }
+}
@@ -0,0 +1,321 @@
+use super::*;
+
+use std::path::Path;
+
+/// A fixed set of colors, which the colors of leaves can be snapped to by [`quantize()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: Vec<PixelType>,
+}
+
+#[non_exhaustive]
+#[derive(Error, Debug)]
+pub enum PaletteError {
+    #[error("the palette has no colors")]
+    Empty,
+    #[error("invalid color `{0}`")]
+    InvalidColor(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl Palette {
+    pub fn new(colors: Vec<PixelType>) -> Result<Self, PaletteError> {
+        match colors.is_empty() {
+            true => Err(PaletteError::Empty),
+            false => Ok(Self { colors }),
+        }
+    }
+
+    /// Parse a list of CSS colors, separated by commas or whitespaces.
+    pub fn parse(list: &str) -> Result<Self, PaletteError> {
+        let mut colors = Vec::new();
+        let mut depth = 0usize;
+        let mut start = 0usize;
+
+        // `rgb(1, 2, 3)` 里面的逗号不算
+        for (i, ch) in list.char_indices().chain([(list.len(), ',')]) {
+            match ch {
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                ',' | ' ' | '\t' | '\r' | '\n' if depth == 0 => {
+                    let item = list[start..i].trim();
+                    if !item.is_empty() {
+                        colors.push(parse_color(item)?);
+                    }
+                    start = i + ch.len_utf8();
+                }
+                _ => (),
+            }
+        }
+
+        Self::new(colors)
+    }
+
+    /// Load a GIMP palette (`.gpl`), a list of hex colors (`.hex`, one per line),
+    /// or a list of CSS colors (any other extension).
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PaletteError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("gpl") => Self::new(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| {
+                        !(l.is_empty()
+                            || l.starts_with('#')
+                            || l.starts_with("GIMP Palette")
+                            || l.starts_with("Name:")
+                            || l.starts_with("Columns:"))
+                    })
+                    .map(|l| {
+                        let rgb = l
+                            .split_whitespace()
+                            .take(3)
+                            .map(str::parse::<u8>)
+                            .collect::<Result<Vec<_>, _>>()
+                            .ok()
+                            .filter(|rgb| rgb.len() == 3)
+                            .ok_or_else(|| PaletteError::InvalidColor(l.into()))?;
+                        Ok::<_, PaletteError>(PixelType::from([rgb[0], rgb[1], rgb[2], 255]))
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some(ext) if ext.eq_ignore_ascii_case("hex") => Self::new(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(|l| parse_color(&format!("#{}", l.trim_start_matches('#'))))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => Self::parse(&content),
+        }
+    }
+
+    /// Compute a palette of (at most) `n` colors from the leaves in the canvas from [`analyze()`],
+    /// by median cut refined with k-means, weighted by the area of leaves.
+    ///
+    /// # 🚧 Panics 🚧
+    ///
+    /// The image size and the canvas must be exactly the same as those passed to [`analyze()`]!
+    pub fn from_canvas(
+        canvas: CanvasView,
+        img_size: (u32, u32),
+        ge_params: GenericParams,
+        n: usize,
+    ) -> Result<Self, PaletteError> {
+        let mut samples = Vec::new();
+        for_each_leaf(canvas, img_size, ge_params, |leaf| {
+            let [r, g, b, _] = leaf.color.0;
+            let weight = (leaf.area_size.0 * leaf.area_size.1) as f32;
+            samples.push(([r as f32, g as f32, b as f32], weight));
+        });
+
+        let mut centers = median_cut(&mut samples, n);
+
+        const ITERATIONS: usize = 8;
+        for _ in 0..ITERATIONS {
+            let mut sums = vec![([0f32; 3], 0f32); centers.len()];
+            for &(rgb, weight) in &samples {
+                let (sum, total) = &mut sums[nearest(&centers, rgb)];
+                (0..3).for_each(|i| sum[i] += rgb[i] * weight);
+                *total += weight;
+            }
+            for (center, (sum, total)) in centers.iter_mut().zip(sums) {
+                if total > 0. {
+                    *center = sum.map(|s| s / total);
+                }
+            }
+        }
+
+        Self::new(
+            centers
+                .into_iter()
+                .map(|c| {
+                    let [r, g, b] = c.map(|ch| ch.round().clamp(0., 255.) as u8);
+                    PixelType::from([r, g, b, 255])
+                })
+                .collect(),
+        )
+    }
+
+    /// The color in this palette closest to the given color. The alpha of the given color is kept.
+    pub fn nearest(&self, color: PixelType) -> PixelType {
+        let dist = |c: &PixelType| {
+            (0..3)
+                .map(|i| (c[i] as i32 - color[i] as i32).pow(2))
+                .sum::<i32>()
+        };
+        let [r, g, b, _] = self.colors.iter().min_by_key(|c| dist(c)).unwrap().0;
+        PixelType::from([r, g, b, color[3]])
+    }
+}
+
+/// Snap the colors of all leaves in the canvas from [`analyze()`] to the palette,
+/// so that [`render()`] paints with the palette only.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image size and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn quantize(
+    canvas: CanvasViewMut,
+    img_size: (u32, u32),
+    ge_params: GenericParams,
+    palette: &Palette,
+) {
+    let mut leaves = Vec::new();
+    for_each_leaf(canvas, img_size, ge_params, |leaf| leaves.push(leaf));
+
+    for Leaf {
+        start_at: (sx, sy),
+        color,
+        ..
+    } in leaves
+    {
+        let (_, c) = &mut canvas[util::pos(img_size.0, sx, sy)];
+        *c = SampleType::from(palette.nearest(color).0);
+    }
+}
+
+fn parse_color(s: &str) -> Result<PixelType, PaletteError> {
+    csscolorparser::parse(s)
+        .map(|c| PixelType::from(c.to_rgba8()))
+        .map_err(|_| PaletteError::InvalidColor(s.into()))
+}
+
+fn nearest(centers: &[[f32; 3]], rgb: [f32; 3]) -> usize {
+    let dist = |c: &[f32; 3]| (0..3).map(|i| (c[i] - rgb[i]).powi(2)).sum::<f32>();
+    (0..centers.len())
+        .min_by(|&a, &b| dist(&centers[a]).total_cmp(&dist(&centers[b])))
+        .unwrap()
+}
+
+/// 每次切开颜色范围最大的那个盒子，切在加权中位数处
+fn median_cut(samples: &mut [([f32; 3], f32)], n: usize) -> Vec<[f32; 3]> {
+    fn widest(samples: &[([f32; 3], f32)]) -> (usize, f32) {
+        (0..3)
+            .map(|ch| {
+                let (min, max) = samples.iter().fold((f32::MAX, f32::MIN), |(lo, hi), s| {
+                    (lo.min(s.0[ch]), hi.max(s.0[ch]))
+                });
+                (ch, max - min)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap()
+    }
+
+    let mut boxes = vec![samples];
+    while boxes.len() < n.max(1) {
+        let Some((i, ch)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest(b)))
+            .filter(|(_, (_, range))| *range > 0.)
+            .max_by(|a, b| a.1 .1.total_cmp(&b.1 .1))
+            .map(|(i, (ch, _))| (i, ch))
+        else {
+            break;
+        };
+
+        let b = boxes.swap_remove(i);
+        b.sort_unstable_by(|x, y| x.0[ch].total_cmp(&y.0[ch]));
+        let half = b.iter().map(|s| s.1).sum::<f32>() / 2.;
+        let mut acc = 0.;
+        let mid = b
+            .iter()
+            .position(|s| {
+                acc += s.1;
+                acc >= half
+            })
+            .unwrap_or(0)
+            .clamp(0, b.len() - 2);
+        let (lo, hi) = b.split_at_mut(mid + 1);
+        boxes.push(lo);
+        boxes.push(hi);
+    }
+
+    boxes
+        .into_iter()
+        .filter(|b| !b.is_empty())
+        .map(|b| {
+            let total = b.iter().map(|s| s.1).sum::<f32>().max(f32::MIN_POSITIVE);
+            let mut c = [0f32; 3];
+            for (rgb, weight) in b.iter() {
+                (0..3).for_each(|i| c[i] += rgb[i] * weight);
+            }
+            c.map(|ch| ch / total)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(r: u8, g: u8, b: u8) -> PixelType {
+        PixelType::from([r, g, b, 255])
+    }
+
+    /// 写到临时目录里再读取，按扩展名区分格式
+    fn loaded(name: &str, content: &str) -> Result<Palette, PaletteError> {
+        let path = std::env::temp_dir().join(format!("quadim-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let palette = Palette::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        palette
+    }
+
+    #[test]
+    fn parse() {
+        let palette = Palette::parse("red, #00ff00 rgb(1, 2, 3)\n\thsl(240, 100%, 50%),").unwrap();
+        assert_eq!(
+            palette.colors,
+            [rgb(255, 0, 0), rgb(0, 255, 0), rgb(1, 2, 3), rgb(0, 0, 255)]
+        );
+
+        assert!(matches!(Palette::parse(" ,\n"), Err(PaletteError::Empty)));
+        assert!(matches!(
+            Palette::parse("red, nocolor"),
+            Err(PaletteError::InvalidColor(c)) if c == "nocolor"
+        ));
+    }
+
+    #[test]
+    fn load() {
+        let gpl =
+            "GIMP Palette\nName: Test\nColumns: 2\n#\n  0   0   0\tBlack\n255 128   1 Orange\n";
+        assert_eq!(
+            loaded("test.gpl", gpl).unwrap().colors,
+            [rgb(0, 0, 0), rgb(255, 128, 1)]
+        );
+        assert!(matches!(
+            loaded("bad.gpl", "GIMP Palette\n1 2\n"),
+            Err(PaletteError::InvalidColor(_))
+        ));
+
+        let hex = "ff0000\n#00FF00\n\n0000ff\n";
+        assert_eq!(
+            loaded("test.hex", hex).unwrap().colors,
+            [rgb(255, 0, 0), rgb(0, 255, 0), rgb(0, 0, 255)]
+        );
+
+        assert_eq!(
+            loaded("test.txt", "rgb(1, 2, 3) white").unwrap().colors,
+            [rgb(1, 2, 3), rgb(255, 255, 255)]
+        );
+    }
+
+    #[test]
+    fn nearest() {
+        let palette = Palette::parse("black, white, red").unwrap();
+        assert_eq!(palette.nearest(rgb(200, 40, 30)), rgb(255, 0, 0));
+        assert_eq!(
+            palette.nearest(PixelType::from([90, 90, 90, 128])),
+            PixelType::from([0, 0, 0, 128])
+        );
+    }
+}
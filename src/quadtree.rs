@@ -0,0 +1,401 @@
+use std::io::{self, Read, Write};
+
+use super::*;
+
+const MAGIC: [u8; 4] = *b"QDIM";
+const VERSION: u8 = 1;
+
+/// The header of a quadtree file from [`save_quadtree()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuadtreeHeader {
+    /// The size of the image analyzed.
+    pub img_size: (u32, u32),
+    pub ge_params: GenericParams,
+    /// The number of leaves in the file.
+    pub leaves: u32,
+}
+
+impl QuadtreeHeader {
+    /// Read the header of a quadtree file, then the leaves can be read by [`load_quadtree()`].
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, QuadtreeError> {
+        let mut buf = [0u8; 4 + 1 + 4 + 4 + 4 + 4];
+        match reader.read_exact(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(QuadtreeError::NotQuadtree)
+            }
+            r => r?,
+        }
+        if buf[..4] != MAGIC {
+            return Err(QuadtreeError::NotQuadtree);
+        }
+        if buf[4] != VERSION {
+            return Err(QuadtreeError::UnsupportedVersion(buf[4]));
+        }
+
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let [ratio_w, ratio_h, max_depth, flags] = [buf[17], buf[18], buf[19], buf[20]];
+        Ok(Self {
+            img_size: (u32_at(5), u32_at(9)),
+            ge_params: GenericParams {
+                slicing_ratio: (ratio_w, ratio_h),
+                max_depth,
+                gradient: flags & 1 != 0,
+            },
+            leaves: u32_at(13),
+        })
+    }
+}
+
+/// Save the quadtree in the canvas from [`analyze()`], so that it can be rendered later
+/// (or elsewhere) by [`load_quadtree()`] and [`render()`], without the image.
+///
+/// The file is small: only the leaves are saved, each as its depth and color (and its gradient, if any).
+///
+/// # 🚧 Panics 🚧
+///
+/// ***WARNING***: `img_size` and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn save_quadtree<W: Write>(
+    writer: &mut W,
+    canvas: CanvasView,
+    img_size: (u32, u32),
+    ge_params: GenericParams,
+) -> io::Result<()> {
+    let GenericParams {
+        slicing_ratio: (ratio_w, ratio_h),
+        max_depth,
+        gradient,
+    } = ge_params;
+
+    // 空的叶子不画出来，也不保存，读取时照样跳过
+    let is_empty = |leaf: &Leaf| leaf.area_size.0 == 0 || leaf.area_size.1 == 0;
+    let mut leaves = 0u32;
+    for_each_leaf(canvas, img_size, ge_params, |leaf| {
+        leaves += !is_empty(&leaf) as u32
+    });
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    writer.write_all(&img_size.0.to_le_bytes())?;
+    writer.write_all(&img_size.1.to_le_bytes())?;
+    writer.write_all(&leaves.to_le_bytes())?;
+    writer.write_all(&[ratio_w, ratio_h, max_depth, gradient as u8])?;
+
+    let mut result = Ok(());
+    for_each_leaf(canvas, img_size, ge_params, |leaf| {
+        if result.is_err() || is_empty(&leaf) {
+            return;
+        }
+        let Leaf {
+            start_at,
+            area_size: (w, h),
+            depth,
+            color,
+        } = leaf;
+        result = (|| {
+            writer.write_all(&[depth])?;
+            writer.write_all(&color.0)?;
+            if gradient && w > 1 && h > 1 {
+                let (slot_x, slot_y) = util::gradient_slots(img_size.0, start_at);
+                writer.write_all(canvas[slot_x].1.as_slice())?;
+                writer.write_all(canvas[slot_y].1.as_slice())?;
+            }
+            Ok(())
+        })();
+    });
+    result
+}
+
+/// Load the leaves of a quadtree file from [`save_quadtree()`] into the canvas,
+/// after its header is read by [`QuadtreeHeader::read()`].
+///
+/// The canvas can then be used as if it is from [`analyze()`],
+/// with the image size and [`GenericParams`] of the header.
+pub fn load_quadtree<R: Read>(
+    reader: &mut R,
+    header: &QuadtreeHeader,
+    canvas: CanvasViewMut,
+) -> Result<(), QuadtreeError> {
+    let QuadtreeHeader {
+        img_size: (img_w, img_h),
+        ge_params,
+        leaves,
+    } = *header;
+
+    if img_w as u64 * img_h as u64 > canvas.len() as u64 {
+        return Err(QuadtreeError::ImageTooLarge);
+    }
+
+    let mut nodes = Nodes {
+        reader,
+        peeked: None,
+        remaining: leaves,
+    };
+    for Tile {
+        start_at,
+        area_size,
+        real_max_depth,
+    } in util::div_grid(
+        header.img_size,
+        ge_params.slicing_ratio,
+        ge_params.max_depth,
+    ) {
+        go_depth(
+            &mut nodes,
+            canvas,
+            img_w,
+            start_at,
+            area_size,
+            ge_params.gradient,
+            real_max_depth.max(1),
+            None,
+        )?;
+    }
+
+    match (nodes.peeked, nodes.remaining) {
+        (None, 0) => Ok(()),
+        _ => Err(QuadtreeError::Corrupted),
+    }
+}
+
+/// 按顺序读取叶子，可以先看一眼下一个叶子的深度
+struct Nodes<'r, R: Read> {
+    reader: &'r mut R,
+    peeked: Option<CanvasPixel>,
+    remaining: u32,
+}
+
+impl<R: Read> Nodes<'_, R> {
+    fn peek(&mut self) -> Result<CanvasPixel, QuadtreeError> {
+        if let Some(node) = self.peeked {
+            return Ok(node);
+        }
+        if self.remaining == 0 {
+            return Err(QuadtreeError::Corrupted);
+        }
+        self.remaining -= 1;
+        let mut buf = [0u8; 1 + CHANNEL_COUNT];
+        self.read_exact(&mut buf)?;
+        let node = (buf[0], SampleType::from_column_slice(&buf[1..]));
+        self.peeked = Some(node);
+        Ok(node)
+    }
+
+    fn take(&mut self) -> Result<CanvasPixel, QuadtreeError> {
+        let node = self.peek()?;
+        self.peeked = None;
+        Ok(node)
+    }
+
+    fn read_slope(&mut self) -> Result<SampleType, QuadtreeError> {
+        let mut buf = [0u8; CHANNEL_COUNT];
+        self.read_exact(&mut buf)?;
+        Ok(SampleType::from(buf))
+    }
+
+    /// 文件提前结束说明它不完整
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), QuadtreeError> {
+        self.reader.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => QuadtreeError::Corrupted,
+            _ => e.into(),
+        })
+    }
+}
+
+/// 和 [`for_each_leaf()`] 的遍历一致：子树最左上的叶子比当前更深，就继续往下
+#[allow(clippy::too_many_arguments)]
+fn go_depth<R: Read>(
+    nodes: &mut Nodes<R>,
+    canvas: CanvasViewMut,
+    img_w: u32,
+    start_at: (u32, u32),
+    area_size: (u32, u32),
+    gradient: bool,
+    max_depth: u8,
+    now_depth: Option<u8>,
+) -> Result<(), QuadtreeError> {
+    let (sx, sy) = start_at;
+    let (w, h) = area_size;
+    if w == 0 || h == 0 {
+        return Ok(());
+    }
+
+    let now_depth = now_depth.unwrap_or(1);
+
+    let (d, _) = nodes.peek()?;
+    // 叶子不会比分块允许的更深，也不会比它所在的位置更浅
+    if d < now_depth || d > max_depth {
+        return Err(QuadtreeError::Corrupted);
+    }
+    if now_depth < d {
+        for (start_at, area_size) in util::div_quad(start_at, area_size) {
+            go_depth(
+                nodes,
+                canvas,
+                img_w,
+                start_at,
+                area_size,
+                gradient,
+                max_depth,
+                Some(now_depth + 1),
+            )?;
+        }
+    } else {
+        let (_, color) = nodes.take()?;
+        canvas[util::pos(img_w, sx, sy)] = (now_depth, color);
+        if gradient && w > 1 && h > 1 {
+            let (slot_x, slot_y) = util::gradient_slots(img_w, start_at);
+            canvas[slot_x] = (now_depth, nodes.read_slope()?);
+            canvas[slot_y] = (now_depth, nodes.read_slope()?);
+        }
+    }
+
+    Ok(())
+}
+
+#[non_exhaustive]
+#[derive(Error, Debug)]
+pub enum QuadtreeError {
+    #[error("not a quadtree file")]
+    NotQuadtree,
+    #[error("unsupported version of quadtree file: {0}")]
+    UnsupportedVersion(u8),
+    #[error("the quadtree file is corrupted")]
+    Corrupted,
+    #[error("the image has more pixels than the canvas' buffer length")]
+    ImageTooLarge,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (67, 49);
+
+    /// 左边是几块平的颜色，右边是噪声，这样叶子有深有浅
+    fn image() -> ImageType {
+        let mut state = 1u32;
+        ImageType::from_fn(SIZE.0, SIZE.1, |x, y| match x < SIZE.0 / 2 {
+            true => PixelType::from([200, 120, (y / 16 * 40) as u8, 255]),
+            false => {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                let [r, g, b, _] = state.to_le_bytes();
+                PixelType::from([r, g, b, 255])
+            }
+        })
+    }
+
+    fn saved(ge_params: GenericParams) -> (Vec<CanvasPixel>, Vec<u8>) {
+        let an_params = AnalyzeParams {
+            thres_ay: 10.,
+            thres_cbcr: 2.,
+            merge_method: MergeMethod::StDev,
+        };
+        let mut canvas = vec![(0u8, SampleType::zeros()); (SIZE.0 * SIZE.1) as usize];
+        analyze(&image(), &mut canvas, ge_params, an_params).unwrap();
+        let mut bytes = Vec::new();
+        save_quadtree(&mut bytes, &canvas, SIZE, ge_params).unwrap();
+        (canvas, bytes)
+    }
+
+    fn loaded(mut bytes: &[u8]) -> Result<(QuadtreeHeader, Vec<CanvasPixel>), QuadtreeError> {
+        let header = QuadtreeHeader::read(&mut bytes)?;
+        let (w, h) = header.img_size;
+        let mut canvas = vec![(0u8, SampleType::zeros()); (w * h) as usize];
+        load_quadtree(&mut bytes, &header, &mut canvas)?;
+        Ok((header, canvas))
+    }
+
+    fn leaves(canvas: CanvasView, ge_params: GenericParams) -> Vec<Leaf> {
+        let mut leaves = Vec::new();
+        for_each_leaf(canvas, SIZE, ge_params, |leaf| leaves.push(leaf));
+        leaves
+    }
+
+    fn rendered(canvas: CanvasView, ge_params: GenericParams) -> ImageType {
+        let mut img = ImageType::new(SIZE.0, SIZE.1);
+        let re_params = RenderParams::new(
+            PixelType::from([255, 255, 255, 255]),
+            PixelType::from([0, 0, 0, 255]),
+            0,
+        );
+        let brush = Box::new(ClassicBrush::Smooth);
+        render(&mut img, canvas, brush, ge_params, re_params, 0.).unwrap();
+        img
+    }
+
+    #[test]
+    fn round_trip() {
+        for gradient in [false, true] {
+            let ge_params = GenericParams::new((4, 3), 5).with_gradient(gradient);
+            let (canvas, bytes) = saved(ge_params);
+            let (header, loaded) = loaded(&bytes).unwrap();
+
+            let depths = leaves(&canvas, ge_params)
+                .iter()
+                .map(|l| l.depth)
+                .collect::<Vec<_>>();
+            assert!(depths.contains(&1) && depths.contains(&4));
+
+            assert_eq!(header.img_size, SIZE);
+            assert_eq!(header.ge_params, ge_params);
+            assert_eq!(header.leaves as usize, leaves(&canvas, ge_params).len());
+            assert_eq!(leaves(&loaded, ge_params), leaves(&canvas, ge_params));
+            assert!(rendered(&loaded, ge_params) == rendered(&canvas, ge_params));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let (_, bytes) = saved(GenericParams::new((1, 1), 6).with_gradient(true));
+        for len in [0, 4, 20] {
+            assert!(matches!(
+                loaded(&bytes[..len]),
+                Err(QuadtreeError::NotQuadtree)
+            ));
+        }
+        for len in [21, 22, bytes.len() / 2, bytes.len() - 1] {
+            assert!(matches!(
+                loaded(&bytes[..len]),
+                Err(QuadtreeError::Corrupted)
+            ));
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        let (_, bytes) = saved(GenericParams::new((1, 1), 6));
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            loaded(&bad_magic),
+            Err(QuadtreeError::NotQuadtree)
+        ));
+
+        let mut bad_version = bytes;
+        bad_version[4] = VERSION + 1;
+        assert!(matches!(
+            loaded(&bad_version),
+            Err(QuadtreeError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn bad_depth() {
+        let ge_params = GenericParams::new((4, 3), 5);
+        let (_, bytes) = saved(ge_params);
+
+        // 头部的最大深度被改小，叶子就比它更深了
+        let mut shallower = bytes.clone();
+        shallower[19] = 2;
+        assert!(matches!(loaded(&shallower), Err(QuadtreeError::Corrupted)));
+
+        for depth in [0, ge_params.max_depth + 1] {
+            let mut bad_leaf = bytes.clone();
+            bad_leaf[21] = depth;
+            assert!(matches!(loaded(&bad_leaf), Err(QuadtreeError::Corrupted)));
+        }
+    }
+}
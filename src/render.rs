@@ -1,8 +1,11 @@
 use super::*;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 
 /// Parameters required by [`render()`].
+///
+/// Made with [`RenderParams::new()`], so that adding a field is not a breaking change.
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RenderParams {
     /// The background color of the fill (if required).
@@ -11,13 +14,86 @@ pub struct RenderParams {
     pub stroke_color: PixelType,
     /// The width of the stroke.
     pub stroke_width: u32,
+    /// Where the stroke lies relative to the edges of nodes.
+    pub stroke_align: StrokeAlign,
+    /// The pattern of the stroke.
+    pub stroke_style: StrokeStyle,
+    /// Only nodes at least this deep are stroked. `0` and `1` stroke all nodes.
+    pub stroke_min_depth: u8,
+
+    /// How to blend the styled image over the original one.
+    pub blend: BlendMode,
+    /// The opacity of the styled image over the original one. `255` means fully styled.
+    pub opacity: u8,
 
-    /// (reserved)
+    /// The random seed for randomized brushes.
     ///
-    /// 自定义笔刷的随机数种子。
+    /// 随机笔刷（例如 [`ClassicBrush::Jitter`]）与脚本笔刷中 `seed` 的随机数种子。
     pub seed: u64,
 }
 
+impl RenderParams {
+    /// With a centered solid stroke on nodes at all depths, blended normally and fully opaque,
+    /// and a seed of `0`. See the `with_*` methods for the rest.
+    pub fn new(bg_color: PixelType, stroke_color: PixelType, stroke_width: u32) -> Self {
+        Self {
+            bg_color,
+            stroke_color,
+            stroke_width,
+            stroke_align: StrokeAlign::Center,
+            stroke_style: StrokeStyle::Solid,
+            stroke_min_depth: 0,
+            blend: BlendMode::Normal,
+            opacity: 255,
+            seed: 0,
+        }
+    }
+
+    /// Sets [`RenderParams::stroke_align`].
+    pub fn with_stroke_align(self, stroke_align: StrokeAlign) -> Self {
+        Self {
+            stroke_align,
+            ..self
+        }
+    }
+
+    /// Sets [`RenderParams::stroke_style`].
+    pub fn with_stroke_style(self, stroke_style: StrokeStyle) -> Self {
+        Self {
+            stroke_style,
+            ..self
+        }
+    }
+
+    /// Sets [`RenderParams::stroke_min_depth`].
+    pub fn with_stroke_min_depth(self, stroke_min_depth: u8) -> Self {
+        Self {
+            stroke_min_depth,
+            ..self
+        }
+    }
+
+    /// Sets [`RenderParams::blend`].
+    pub fn with_blend(self, blend: BlendMode) -> Self {
+        Self { blend, ..self }
+    }
+
+    /// Sets [`RenderParams::opacity`].
+    pub fn with_opacity(self, opacity: u8) -> Self {
+        Self { opacity, ..self }
+    }
+
+    /// Sets [`RenderParams::seed`].
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// Whether a node at this depth is stroked.
+    pub fn is_stroked(&self, now_depth: u8) -> bool {
+        self.stroke_width > 0 && now_depth >= self.stroke_min_depth
+    }
+}
+
 /// Reconstruct the styled image in-place from the canvas from [`analyze()`].
 ///
 /// # 🚧 Panics 🚧
@@ -30,90 +106,248 @@ pub struct RenderParams {
 pub fn render(
     img: &mut ImageType,
     canvas: CanvasView,
-    mut brush: Box<dyn Brush>,
+    brush: Box<dyn Brush>,
     ge_params: GenericParams,
     re_params: RenderParams,
     time_elapsed: f32,
 ) -> Result<(), RenderError> {
-    let GenericParams {
-        slicing_ratio,
-        max_depth,
-    } = ge_params;
+    let analyzed_size = img.dimensions();
+    render_scaled(
+        img,
+        analyzed_size,
+        canvas,
+        brush,
+        ge_params,
+        re_params,
+        time_elapsed,
+    )
+}
+
+/// Like [`render()`], but onto an image of a different size than the one passed to [`analyze()`],
+/// e.g. analyze a downscaled image for speed, then render a large print.
+///
+/// Each node is scaled so that adjacent nodes still adjoin exactly.
+/// When blending (see [`RenderParams::blend`]), the original content of `img` is the bottom layer.
+///
+/// # 🚧 Panics 🚧
+///
+/// ***WARNING***: `analyzed_size` and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn render_scaled(
+    img: &mut ImageType,
+    analyzed_size: (u32, u32),
+    canvas: CanvasView,
+    brush: Box<dyn Brush>,
+    ge_params: GenericParams,
+    re_params: RenderParams,
+    time_elapsed: f32,
+) -> Result<(), RenderError> {
+    let original = match (re_params.blend, re_params.opacity) {
+        (BlendMode::Normal, 255) => None,
+        _ => Some(img.clone()),
+    };
 
     if brush.need_background() {
         img.pixels_mut().for_each(|p| *p = re_params.bg_color);
     }
 
-    for Tile {
-        start_at,
-        area_size,
-        real_max_depth: _,
-    } in util::div_grid(img.dimensions(), slicing_ratio, max_depth)
-    {
-        go_depth(
-            img,
-            canvas,
-            start_at,
-            area_size,
-            re_params,
-            time_elapsed,
-            None,
-            &mut brush,
-        )?;
+    let rescale = util::Rescale::new(analyzed_size, img.dimensions());
+    for_each_leaf(
+        canvas,
+        analyzed_size,
+        ge_params,
+        |Leaf {
+             start_at,
+             area_size,
+             depth,
+             color,
+         }| {
+            let (out_at, out_size) = rescale.rect(start_at, area_size);
+            if out_size.0 == 0 || out_size.1 == 0 {
+                return;
+            }
+
+            if ge_params.gradient && area_size.0 > 1 && area_size.1 > 1 {
+                let (slot_x, slot_y) = util::gradient_slots(analyzed_size.0, start_at);
+                brush.paint_gradient(
+                    img,
+                    re_params,
+                    out_at,
+                    out_size,
+                    time_elapsed,
+                    depth,
+                    color,
+                    Gradient {
+                        dx: util::decode_slope(canvas[slot_x].1),
+                        dy: util::decode_slope(canvas[slot_y].1),
+                    },
+                );
+            } else {
+                brush.paint(img, re_params, out_at, out_size, time_elapsed, depth, color);
+            }
+        },
+    );
+
+    if let Some(e) = brush.take_error() {
+        return Err(RenderError::Brush(e));
     }
+
+    if let Some(original) = original {
+        re_params.blend.composite(img, &original, re_params.opacity);
+    }
+
     Ok(())
 }
 
-fn go_depth(
+/// Draw the edges between nodes over `img`, as a network of lines derived from the quadtree
+/// rather than from per-node strokes, e.g. after [`render()`] with a zero stroke width.
+///
+/// Every edge, including the frame of the image, is drawn exactly once with the same width.
+/// Only [`RenderParams::stroke_color`], [`RenderParams::stroke_width`] (at least 1 pixel)
+/// and [`RenderParams::stroke_style`] are used.
+///
+/// # 🚧 Panics 🚧
+///
+/// ***WARNING***: `analyzed_size` and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn render_grid(
     img: &mut ImageType,
+    analyzed_size: (u32, u32),
     canvas: CanvasView,
-    start_at: (u32, u32),
-    area_size: (u32, u32),
+    ge_params: GenericParams,
     re_params: RenderParams,
-    time_elapsed: f32,
-    now_depth: Option<u8>,
-    brush: &mut Box<dyn Brush>,
-) -> Result<(), RenderError> {
-    let (img_w, _) = img.dimensions();
-    let (sx, sy) = start_at;
+) {
+    let (iw, ih) = img.dimensions();
+    let sw = re_params.stroke_width.max(1);
 
-    let now_depth = now_depth.unwrap_or(1);
+    // 线条以边为中心，但贴着图像边缘的要整个挪进来
+    let band = |e: u32, size: u32| -> (i32, u32) {
+        let start = e.saturating_sub(sw >> 1).min(size.saturating_sub(sw));
+        (start as i32, sw.min(size))
+    };
+    let draw = |img: &mut ImageType, (x, y): (i32, i32), (w, h): (u32, u32), along_x| {
+        draw_band(
+            img,
+            (x, y),
+            (w, h),
+            along_x,
+            sw,
+            re_params.stroke_style,
+            re_params.stroke_color,
+        )
+    };
 
-    let (d, c) = canvas[util::pos(img_w, sx, sy)];
-    if now_depth < d {
-        for (start_at, area_size) in util::div_quad(start_at, area_size) {
-            go_depth(
-                img,
-                canvas,
-                start_at,
-                area_size,
-                re_params,
-                time_elapsed,
-                Some(now_depth + 1),
-                brush,
-            )?;
+    // 每个节点只画上边和左边，贴着图像右下边缘的再补上右边和下边
+    let rescale = util::Rescale::new(analyzed_size, (iw, ih));
+    for_each_leaf(canvas, analyzed_size, ge_params, |leaf| {
+        let ((x, y), (w, h)) = rescale.rect(leaf.start_at, leaf.area_size);
+        if w == 0 || h == 0 {
+            return;
+        }
+
+        let (top, bh) = band(y, ih);
+        draw(img, (x as i32, top), (w, bh), true);
+        let (left, bw) = band(x, iw);
+        draw(img, (left, y as i32), (bw, h), false);
+        if y + h >= ih {
+            let (bottom, bh) = band(ih, ih);
+            draw(img, (x as i32, bottom), (w, bh), true);
+        }
+        if x + w >= iw {
+            let (right, bw) = band(iw, iw);
+            draw(img, (right, y as i32), (bw, h), false);
+        }
+    });
+}
+
+#[non_exhaustive]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    #[error("the brush failed: {0}")]
+    Brush(String),
+}
+
+/// Specifies how to blend the styled image (the "top" layer) over the original one (the "bottom" layer).
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Difference,
+}
+
+impl BlendMode {
+    /// Blend `top` over `bottom` in-place, then mix it with `bottom` by `opacity`.
+    /// The alpha channel is only mixed.
+    fn composite(self, top: &mut ImageType, bottom: &ImageType, opacity: u8) {
+        let opacity = opacity as f32 / 255.;
+
+        for (t, b) in top.pixels_mut().zip(bottom.pixels()) {
+            for ch in 0..CHANNEL_COUNT {
+                let (tv, bv) = (t[ch] as f32 / 255., b[ch] as f32 / 255.);
+                let blended = match ch {
+                    3 => tv,
+                    _ => match self {
+                        BlendMode::Normal => tv,
+                        BlendMode::Multiply => bv * tv,
+                        BlendMode::Screen => 1. - (1. - bv) * (1. - tv),
+                        BlendMode::Overlay => match bv < 0.5 {
+                            true => 2. * bv * tv,
+                            false => 1. - 2. * (1. - bv) * (1. - tv),
+                        },
+                        BlendMode::Difference => (bv - tv).abs(),
+                    },
+                };
+                let mixed = bv + (blended - bv) * opacity;
+                t[ch] = (mixed * 255.).round().clamp(0., 255.) as u8;
+            }
         }
-    } else {
-        brush.paint(
-            img,
-            re_params,
-            start_at,
-            area_size,
-            time_elapsed,
-            now_depth,
-            PixelType::from(Into::<[DepthType; CHANNEL_COUNT]>::into(c)),
-        );
     }
+}
 
-    Ok(())
+/// Specifies where the stroke lies relative to the edges of nodes.
+///
+/// Every edge shared by two nodes is stroked only once, so lines are always as wide as the stroke.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum StrokeAlign {
+    /// Inside the nodes. The frame of the image is as wide as the other lines.
+    Inner,
+    /// Centered on the edges. The frame of the image is half as wide as the other lines.
+    Center,
+    /// Outside the nodes. The frame of the image is not stroked.
+    Outer,
 }
 
+/// Specifies the pattern of the stroke.
 #[non_exhaustive]
-#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RenderError {}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum StrokeStyle {
+    Solid,
+    /// Dashes are laid out by the absolute position in the image, so that they line up across nodes.
+    Dashed,
+}
+
+/// 支持从 u8 索引生成 BlendMode
+impl From<u8> for BlendMode {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => BlendMode::Normal,
+            1 => BlendMode::Multiply,
+            2 => BlendMode::Screen,
+            3 => BlendMode::Overlay,
+            4 => BlendMode::Difference,
+            _ => BlendMode::Normal,
+        }
+    }
+}
 
 /// Interface for custom brushes.
 pub trait Brush: Debug + Send + Sync {
+    #[allow(clippy::too_many_arguments)]
     fn paint(
         &self,
         img: &mut ImageType,
@@ -125,9 +359,97 @@ pub trait Brush: Debug + Send + Sync {
         color: PixelType,
     );
 
+    /// Paint a node whose color varies linearly across it, see [`GenericParams::gradient`].
+    ///
+    /// By default, the gradient is ignored.
+    #[allow(clippy::too_many_arguments)]
+    fn paint_gradient(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        start_at: (u32, u32),
+        area_size: (u32, u32),
+        time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+        _gradient: Gradient,
+    ) {
+        self.paint(
+            img,
+            params,
+            start_at,
+            area_size,
+            time_elapsed,
+            now_depth,
+            color,
+        )
+    }
+
+    /// Take the first error hit while painting since the last call, if the brush can fail (e.g. [`ScriptBrush`]).
+    ///
+    /// [`render()`] calls this after painting, and fails with [`RenderError::Brush`] if there is one.
+    fn take_error(&self) -> Option<String> {
+        None
+    }
+
     fn need_background(&self) -> bool;
 }
 
+/// Allows one brush (e.g. a heavy [`SpriteBrush`]) to be shared by many renderings.
+impl<B: Brush + ?Sized> Brush for Arc<B> {
+    fn paint(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        start_at: (u32, u32),
+        area_size: (u32, u32),
+        time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+    ) {
+        (**self).paint(
+            img,
+            params,
+            start_at,
+            area_size,
+            time_elapsed,
+            now_depth,
+            color,
+        )
+    }
+
+    fn paint_gradient(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        start_at: (u32, u32),
+        area_size: (u32, u32),
+        time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+        gradient: Gradient,
+    ) {
+        (**self).paint_gradient(
+            img,
+            params,
+            start_at,
+            area_size,
+            time_elapsed,
+            now_depth,
+            color,
+            gradient,
+        )
+    }
+
+    fn take_error(&self) -> Option<String> {
+        (**self).take_error()
+    }
+
+    fn need_background(&self) -> bool {
+        (**self).need_background()
+    }
+}
+
 /// Built-in brush kinds.
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -138,17 +460,27 @@ pub enum ClassicBrush {
     Cross,
     YrAdd,
     YrMul,
+    Jitter,
+    Rotate,
+    Shift,
+    Scatter,
+    Smooth,
 }
 
 impl Brush for ClassicBrush {
     fn paint(
         &self,
         img: &mut ImageType,
-        RenderParams {
+        params @ RenderParams {
             bg_color: _,
             stroke_color,
             stroke_width,
-            seed: _,
+            stroke_align: _,
+            stroke_style: _,
+            stroke_min_depth: _,
+            blend: _,
+            opacity: _,
+            seed,
         }: RenderParams,
         (sx, sy): (u32, u32),
         (w, h): (u32, u32),
@@ -159,56 +491,13 @@ impl Brush for ClassicBrush {
         use imageproc::{drawing::*, rect::Rect};
 
         let (sx, sy) = (sx as i32, sy as i32);
-        let with_stroke = stroke_width > 0;
-
-        fn draw_rect_inner_stroke(
-            img: &mut ImageType,
-            (sx, sy): (i32, i32),
-            (w, h): (u32, u32),
-            stroke_width: u32,
-            color: PixelType,
-        ) {
-            if stroke_width >= w.min(h) {
-                draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color);
-                return;
-            }
-            let se = stroke_width >> 1;
-            let ss = se + (stroke_width & 1);
-
-            /*
-            ,-----------= sx
-            | ,---------= sx + ss
-            | |     ,---= sx + w - se
-            | |     | ,-= sx + w
-            v v     v v
-            ,-+--1--+-. = sy
-            |-|-----|-| = sy + ss
-            |2|     |4|
-            |-|-----|-| = sy + h - se
-            `-+--3--+-' = sy + h
-            */
-
-            draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, ss), color);
-            draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(ss, h), color);
-            if se > 0 {
-                draw_filled_rect_mut(
-                    img,
-                    Rect::at(sx, sy + h as i32 - se as i32).of_size(w, se),
-                    color,
-                );
-                draw_filled_rect_mut(
-                    img,
-                    Rect::at(sx + w as i32 - se as i32, sy).of_size(se, h),
-                    color,
-                );
-            }
-        }
+        let with_stroke = params.is_stroked(now_depth);
 
         match self {
-            ClassicBrush::Rect => {
+            ClassicBrush::Rect | ClassicBrush::Smooth => {
                 draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color);
                 if with_stroke {
-                    draw_rect_inner_stroke(img, (sx, sy), (w, h), stroke_width, stroke_color);
+                    draw_rect_stroke(img, (sx, sy), (w, h), params, stroke_color);
                 }
             }
             ClassicBrush::Circle => {
@@ -216,7 +505,7 @@ impl Brush for ClassicBrush {
                 let center = (sx + rw, sy + rh);
                 draw_filled_ellipse_mut(img, center, rw, rh, color);
                 if with_stroke {
-                    draw_hollow_ellipse_mut(img, center, rw, rh, stroke_color);
+                    draw_ellipse_stroke(img, center, (rw, rh), params, stroke_color);
                 }
             }
             ClassicBrush::Cross => {
@@ -239,7 +528,11 @@ impl Brush for ClassicBrush {
                 let color: PixelType = csscolorparser::Color::from_hsla(hue, 0.8, lit, 1.0)
                     .to_rgba8()
                     .into();
-                draw_rect_inner_stroke(img, (sx, sy), (w, h), stroke_width.max(1), color);
+                let params = RenderParams {
+                    stroke_width: stroke_width.max(1),
+                    ..params
+                };
+                draw_rect_stroke(img, (sx, sy), (w, h), params, color);
             }
             ClassicBrush::YrMul => match now_depth {
                 1..=2 => draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color),
@@ -258,6 +551,123 @@ impl Brush for ClassicBrush {
                     draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color);
                 }
             },
+            ClassicBrush::Jitter => {
+                let mut rng = util::Rng::new(seed, (sx as u32, sy as u32), now_depth);
+                let scale = 0.75 + 0.25 * rng.next_f32();
+                let (jw, jh) = (
+                    ((w as f32 * scale) as u32).max(1),
+                    ((h as f32 * scale) as u32).max(1),
+                );
+                let (jx, jy) = (
+                    sx + ((w - jw) as f32 / 2. * (1. + rng.next_signed())) as i32,
+                    sy + ((h - jh) as f32 / 2. * (1. + rng.next_signed())) as i32,
+                );
+                draw_filled_rect_mut(img, Rect::at(jx, jy).of_size(jw, jh), color);
+                if with_stroke {
+                    draw_rect_outline(img, (jx, jy), (jw, jh), params, stroke_color);
+                }
+            }
+            ClassicBrush::Rotate if w.min(h) < 3 => {
+                draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color);
+                if with_stroke {
+                    draw_rect_stroke(img, (sx, sy), (w, h), params, stroke_color);
+                }
+            }
+            ClassicBrush::Rotate => {
+                let mut rng = util::Rng::new(seed, (sx as u32, sy as u32), now_depth);
+                let angle = rng.next_signed() * std::f32::consts::FRAC_PI_4;
+                let (cx, cy) = (sx as f32 + w as f32 / 2., sy as f32 + h as f32 / 2.);
+                let (rw, rh) = (w as f32 / 2., h as f32 / 2.);
+                let (sin, cos) = angle.sin_cos();
+                let corners = [(-rw, -rh), (rw, -rh), (rw, rh), (-rw, rh)].map(|(x, y)| {
+                    imageproc::point::Point::new(
+                        (cx + x * cos - y * sin).round() as i32,
+                        (cy + x * sin + y * cos).round() as i32,
+                    )
+                });
+                draw_polygon_mut(img, &corners, color);
+                if with_stroke {
+                    draw_rotated_rect_stroke(img, (cx, cy), (rw, rh), angle, params, stroke_color);
+                }
+            }
+            ClassicBrush::Shift => {
+                let mut rng = util::Rng::new(seed, (sx as u32, sy as u32), now_depth);
+                let [r, g, b, a] = color.0;
+                let shift = |c: u8, d: f32| (c as f32 + d * 32.).round().clamp(0., 255.) as u8;
+                let color = PixelType::from([
+                    shift(r, rng.next_signed()),
+                    shift(g, rng.next_signed()),
+                    shift(b, rng.next_signed()),
+                    a,
+                ]);
+                draw_filled_rect_mut(img, Rect::at(sx, sy).of_size(w, h), color);
+                if with_stroke {
+                    draw_rect_stroke(img, (sx, sy), (w, h), params, stroke_color);
+                }
+            }
+            ClassicBrush::Scatter => {
+                let mut rng = util::Rng::new(seed, (sx as u32, sy as u32), now_depth);
+                let radius = (w.min(h) / 6).max(1);
+                let count = (w * h / (radius * radius * 6)).clamp(1, 256);
+                for _ in 0..count {
+                    let center = (
+                        sx + (rng.next_f32() * w as f32) as i32,
+                        sy + (rng.next_f32() * h as f32) as i32,
+                    );
+                    draw_filled_circle_mut(img, center, radius as i32, color);
+                    if with_stroke {
+                        let r = radius as i32;
+                        draw_ellipse_stroke(img, center, (r, r), params, stroke_color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn paint_gradient(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        (sx, sy): (u32, u32),
+        (w, h): (u32, u32),
+        time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+        gradient: Gradient,
+    ) {
+        if *self != ClassicBrush::Smooth {
+            return self.paint(
+                img,
+                params,
+                (sx, sy),
+                (w, h),
+                time_elapsed,
+                now_depth,
+                color,
+            );
+        }
+
+        for y in 0..h {
+            for x in 0..w {
+                img.put_pixel(
+                    sx + x,
+                    sy + y,
+                    gradient.at(
+                        color,
+                        (x as f32 + 0.5) / w as f32,
+                        (y as f32 + 0.5) / h as f32,
+                    ),
+                );
+            }
+        }
+        if params.is_stroked(now_depth) {
+            draw_rect_stroke(
+                img,
+                (sx as i32, sy as i32),
+                (w, h),
+                params,
+                params.stroke_color,
+            );
         }
     }
 
@@ -268,10 +678,280 @@ impl Brush for ClassicBrush {
             ClassicBrush::Cross => true,
             ClassicBrush::YrAdd => true,
             ClassicBrush::YrMul => false,
+            ClassicBrush::Jitter => true,
+            ClassicBrush::Rotate => true,
+            ClassicBrush::Shift => false,
+            ClassicBrush::Scatter => true,
+            ClassicBrush::Smooth => false,
+        }
+    }
+}
+
+/// Stroke the edges of a node, following [`RenderParams::stroke_align`] and [`RenderParams::stroke_style`].
+///
+/// Meant for nodes that tile the image: each shared edge is drawn by only one of the two nodes,
+/// see [`draw_rect_outline()`] for standalone rectangles.
+pub(crate) fn draw_rect_stroke(
+    img: &mut ImageType,
+    (sx, sy): (i32, i32),
+    (w, h): (u32, u32),
+    RenderParams {
+        stroke_width,
+        stroke_align,
+        stroke_style,
+        ..
+    }: RenderParams,
+    color: PixelType,
+) {
+    if stroke_width == 0 || w == 0 || h == 0 {
+        return;
+    }
+    let (ex, ey) = (sx + w as i32, sy + h as i32);
+    let (at_right, at_bottom) = (ex >= img.width() as i32, ey >= img.height() as i32);
+    let only = |b: bool| if b { stroke_width } else { 0 };
+
+    // 共享的边只由一侧的节点来画，这样线宽总是一致的
+    let (top, left, bottom, right) = match stroke_align {
+        StrokeAlign::Center => {
+            let se = stroke_width >> 1;
+            let ss = se + (stroke_width & 1);
+            (ss, ss, se, se)
+        }
+        // 共享边画在右下方的节点里，图像边框由贴边的节点画
+        StrokeAlign::Inner => (stroke_width, stroke_width, only(at_bottom), only(at_right)),
+        // 共享边画在左上方的节点里，也就是右下方节点的外面
+        StrokeAlign::Outer => (0, 0, only(!at_bottom), only(!at_right)),
+    };
+    draw_frame(
+        img,
+        (sx, sy),
+        (w, h),
+        (top, left, bottom, right),
+        stroke_width,
+        stroke_style,
+        color,
+    );
+}
+
+/// Stroke all four sides of a standalone rectangle, following [`RenderParams::stroke_align`] and [`RenderParams::stroke_style`].
+///
+/// Unlike [`draw_rect_stroke()`], no edge is left to the neighbours,
+/// so this is for shapes that don't tile the image, e.g. [`ClassicBrush::Jitter`].
+pub(crate) fn draw_rect_outline(
+    img: &mut ImageType,
+    (sx, sy): (i32, i32),
+    (w, h): (u32, u32),
+    RenderParams {
+        stroke_width,
+        stroke_align,
+        stroke_style,
+        ..
+    }: RenderParams,
+    color: PixelType,
+) {
+    if stroke_width == 0 || w == 0 || h == 0 {
+        return;
+    }
+    // 描边的外缘比矩形多出来的宽度
+    let grow = match stroke_align {
+        StrokeAlign::Inner => 0,
+        StrokeAlign::Center => stroke_width >> 1,
+        StrokeAlign::Outer => stroke_width,
+    };
+    let (w, h) = (w + grow * 2, h + grow * 2);
+    draw_frame(
+        img,
+        (sx - grow as i32, sy - grow as i32),
+        (w, h),
+        (stroke_width, stroke_width, stroke_width, stroke_width),
+        stroke_width,
+        stroke_style,
+        color,
+    );
+}
+
+/// 沿矩形的内侧画四条边，宽度按 `(top, left, bottom, right)` 给出
+fn draw_frame(
+    img: &mut ImageType,
+    (sx, sy): (i32, i32),
+    (w, h): (u32, u32),
+    (top, left, bottom, right): (u32, u32, u32, u32),
+    stroke_width: u32,
+    stroke_style: StrokeStyle,
+    color: PixelType,
+) {
+    let (ex, ey) = (sx + w as i32, sy + h as i32);
+    let (top, bottom) = (top.min(h), bottom.min(h));
+    let (left, right) = (left.min(w), right.min(w));
+
+    let band = |img: &mut ImageType, (x, y): (i32, i32), (bw, bh): (u32, u32), along_x: bool| {
+        if bw > 0 && bh > 0 {
+            draw_band(
+                img,
+                (x, y),
+                (bw, bh),
+                along_x,
+                stroke_width,
+                stroke_style,
+                color,
+            );
+        }
+    };
+    band(img, (sx, sy), (w, top), true);
+    band(img, (sx, ey - bottom as i32), (w, bottom), true);
+    band(img, (sx, sy), (left, h), false);
+    band(img, (ex - right as i32, sy), (right, h), false);
+}
+
+/// 用 `along_x` 指定虚线沿哪个方向排列
+fn draw_band(
+    img: &mut ImageType,
+    (x, y): (i32, i32),
+    (w, h): (u32, u32),
+    along_x: bool,
+    stroke_width: u32,
+    stroke_style: StrokeStyle,
+    color: PixelType,
+) {
+    use imageproc::{drawing::draw_filled_rect_mut, rect::Rect};
+
+    match stroke_style {
+        StrokeStyle::Solid => draw_filled_rect_mut(img, Rect::at(x, y).of_size(w, h), color),
+        StrokeStyle::Dashed => {
+            let dash = dash_length(stroke_width) as i32;
+            let (x0, y0) = (x.max(0), y.max(0));
+            let x1 = (x + w as i32).min(img.width() as i32);
+            let y1 = (y + h as i32).min(img.height() as i32);
+            for py in y0..y1 {
+                for px in x0..x1 {
+                    if (if along_x { px } else { py } / dash) % 2 == 0 {
+                        img.put_pixel(px as u32, py as u32, color);
+                    }
+                }
+            }
         }
     }
 }
 
+/// Stroke the outline of an ellipse, following [`RenderParams::stroke_align`] and [`RenderParams::stroke_style`].
+pub(crate) fn draw_ellipse_stroke(
+    img: &mut ImageType,
+    (cx, cy): (i32, i32),
+    (rx, ry): (i32, i32),
+    RenderParams {
+        stroke_width,
+        stroke_align,
+        stroke_style,
+        ..
+    }: RenderParams,
+    color: PixelType,
+) {
+    let sw = stroke_width as f32;
+    let (inner, outer) = match stroke_align {
+        StrokeAlign::Inner => (-sw, 0.),
+        StrokeAlign::Center => (-sw / 2., sw / 2.),
+        StrokeAlign::Outer => (0., sw),
+    };
+    let (rx, ry) = (rx as f32, ry as f32);
+    let (ox, oy) = (rx + outer, ry + outer);
+    if stroke_width == 0 || ox <= 0. || oy <= 0. {
+        return;
+    }
+
+    let inside = |(a, b): (f32, f32), (dx, dy): (f32, f32)| {
+        a > 0. && b > 0. && (dx / a).powi(2) + (dy / b).powi(2) <= 1.
+    };
+    let dash = dash_length(stroke_width) as f32;
+    let arc = (rx + ry) / 2.;
+
+    let x0 = (cx - ox.ceil() as i32).max(0);
+    let y0 = (cy - oy.ceil() as i32).max(0);
+    let x1 = (cx + ox.ceil() as i32 + 1).min(img.width() as i32);
+    let y1 = (cy + oy.ceil() as i32 + 1).min(img.height() as i32);
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let d = ((px - cx) as f32, (py - cy) as f32);
+            if !inside((ox, oy), d) || inside((rx + inner, ry + inner), d) {
+                continue;
+            }
+            // 椭圆上的虚线按弧长排列
+            if stroke_style == StrokeStyle::Dashed {
+                let len = (d.1.atan2(d.0) + std::f32::consts::PI) * arc;
+                if (len / dash) as u32 % 2 == 1 {
+                    continue;
+                }
+            }
+            img.put_pixel(px as u32, py as u32, color);
+        }
+    }
+}
+
+/// Stroke the outline of a rectangle rotated by `angle` (in radians) around its center,
+/// following [`RenderParams::stroke_align`] and [`RenderParams::stroke_style`].
+pub(crate) fn draw_rotated_rect_stroke(
+    img: &mut ImageType,
+    (cx, cy): (f32, f32),
+    (rw, rh): (f32, f32),
+    angle: f32,
+    RenderParams {
+        stroke_width,
+        stroke_align,
+        stroke_style,
+        ..
+    }: RenderParams,
+    color: PixelType,
+) {
+    let sw = stroke_width as f32;
+    let (inner, outer) = match stroke_align {
+        StrokeAlign::Inner => (-sw, 0.),
+        StrokeAlign::Center => (-sw / 2., sw / 2.),
+        StrokeAlign::Outer => (0., sw),
+    };
+    let (ow, oh) = (rw + outer, rh + outer);
+    if stroke_width == 0 || ow <= 0. || oh <= 0. {
+        return;
+    }
+
+    let dash = dash_length(stroke_width) as f32;
+    let (sin, cos) = angle.sin_cos();
+    // 外接矩形足够大，不用精确
+    let reach = (ow * ow + oh * oh).sqrt().ceil() as i32;
+    let (icx, icy) = (cx as i32, cy as i32);
+
+    let x0 = (icx - reach).max(0);
+    let y0 = (icy - reach).max(0);
+    let x1 = (icx + reach + 1).min(img.width() as i32);
+    let y1 = (icy + reach + 1).min(img.height() as i32);
+    for py in y0..y1 {
+        for px in x0..x1 {
+            // 转回矩形自己的坐标系
+            let (dx, dy) = (px as f32 + 0.5 - cx, py as f32 + 0.5 - cy);
+            let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+            if u.abs() > ow || v.abs() > oh {
+                continue;
+            }
+            if u.abs() < rw + inner && v.abs() < rh + inner {
+                continue;
+            }
+            // 虚线沿着最近的边排列
+            if stroke_style == StrokeStyle::Dashed {
+                let along = match rh - v.abs() < rw - u.abs() {
+                    true => u + rw,
+                    false => v + rh,
+                };
+                if (along.max(0.) / dash) as u32 % 2 == 1 {
+                    continue;
+                }
+            }
+            img.put_pixel(px as u32, py as u32, color);
+        }
+    }
+}
+
+fn dash_length(stroke_width: u32) -> u32 {
+    (stroke_width * 3).max(4)
+}
+
 /// 支持从 u8 索引生成 ClassicBrush
 impl From<u8> for ClassicBrush {
     fn from(v: u8) -> Self {
@@ -281,6 +961,11 @@ impl From<u8> for ClassicBrush {
             2 => ClassicBrush::Cross,
             3 => ClassicBrush::YrAdd,
             4 => ClassicBrush::YrMul,
+            5 => ClassicBrush::Jitter,
+            6 => ClassicBrush::Rotate,
+            7 => ClassicBrush::Shift,
+            8 => ClassicBrush::Scatter,
+            9 => ClassicBrush::Smooth,
             _ => ClassicBrush::Rect,
         }
     }
@@ -0,0 +1,441 @@
+use super::*;
+
+use std::{cell::RefCell, fmt};
+
+use imageproc::{drawing::*, rect::Rect};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST, FLOAT, INT};
+
+/// A brush scripted in [Rhai](https://rhai.rs), so that brushes can be created without recompiling.
+///
+/// The script runs once for each node, with these variables in scope:
+///
+/// - `x`, `y`, `w`, `h`: The position and size of the node.
+/// - `depth`: The depth of the node.
+/// - `color`: The color of the node, as `[r, g, b, a]`.
+/// - `time`: The time elapsed, in seconds.
+/// - `seed`: [`RenderParams::seed`].
+///
+/// And these functions to paint with, where colors are also `[r, g, b, a]` (or `[r, g, b]`):
+///
+/// - `fill_rect(x, y, w, h, color)`
+/// - `stroke_rect(x, y, w, h, width, color)`
+/// - `fill_ellipse(cx, cy, rx, ry, color)`
+/// - `stroke_ellipse(cx, cy, rx, ry, color)`
+/// - `line(x0, y0, x1, y1, color)`
+/// - `pixel(x, y, color)`
+/// - `rgba(r, g, b, a)` and `hsla(h, s, l, a)` to make colors, where `h` is in degrees and the others are in `0.0..=1.0`.
+///
+/// The background is always filled before painting.
+/// Each run is limited (in operations, call depth and string size), so a runaway script fails instead of hanging,
+/// and shapes are clipped to the image, so huge coordinates draw only what is visible.
+/// If the script fails on any node, rendering fails with [`RenderError::Brush`], reporting the first error.
+pub struct ScriptBrush {
+    engine: Engine,
+    ast: AST,
+}
+
+#[non_exhaustive]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    #[error("failed to compile the brush script: {0}")]
+    Compile(String),
+    #[error("the brush script failed on a trial run: {0}")]
+    Runtime(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    FillRect((i32, i32), (u32, u32), PixelType),
+    StrokeRect((i32, i32), (u32, u32), u32, PixelType),
+    FillEllipse((i32, i32), (i32, i32), PixelType),
+    StrokeEllipse((i32, i32), (i32, i32), PixelType),
+    Line((f32, f32), (f32, f32), PixelType),
+    Pixel((i64, i64), PixelType),
+}
+
+thread_local! {
+    // 脚本只负责记录形状，画到图像上是之后的事
+    static SHAPES: RefCell<Vec<Shape>> = const { RefCell::new(Vec::new()) };
+    // 一次渲染只在一个线程里进行，所以第一个出错的节点记在这里，渲染完再取走
+    static FIRST_ERROR: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// 每个节点运行一次脚本的上限
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 64 * 1024;
+
+impl ScriptBrush {
+    /// Compile the script, and try it on a made-up node to catch errors early.
+    pub fn new(script: &str) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ARRAY_SIZE);
+
+        fn record(shape: Shape) {
+            SHAPES.with(|s| s.borrow_mut().push(shape));
+        }
+        fn clamp(v: INT) -> i32 {
+            v.clamp(i32::MIN as INT, i32::MAX as INT) as i32
+        }
+        fn size(v: INT) -> u32 {
+            v.clamp(0, u32::MAX as INT) as u32
+        }
+        type Res = Result<(), Box<EvalAltResult>>;
+
+        engine
+            .register_fn("rgba", |r: INT, g: INT, b: INT, a: INT| -> Array {
+                [r, g, b, a].into_iter().map(Dynamic::from_int).collect()
+            })
+            .register_fn("hsla", |h: FLOAT, s: FLOAT, l: FLOAT, a: FLOAT| -> Array {
+                csscolorparser::Color::from_hsla(h as f64, s as f64, l as f64, a as f64)
+                    .to_rgba8()
+                    .into_iter()
+                    .map(|c| Dynamic::from_int(c as INT))
+                    .collect()
+            })
+            .register_fn(
+                "fill_rect",
+                |x: INT, y: INT, w: INT, h: INT, c: Array| -> Res {
+                    record(Shape::FillRect(
+                        (clamp(x), clamp(y)),
+                        (size(w), size(h)),
+                        to_color(c)?,
+                    ));
+                    Ok(())
+                },
+            )
+            .register_fn(
+                "stroke_rect",
+                |x: INT, y: INT, w: INT, h: INT, width: INT, c: Array| -> Res {
+                    record(Shape::StrokeRect(
+                        (clamp(x), clamp(y)),
+                        (size(w), size(h)),
+                        size(width),
+                        to_color(c)?,
+                    ));
+                    Ok(())
+                },
+            )
+            .register_fn(
+                "fill_ellipse",
+                |x: INT, y: INT, rx: INT, ry: INT, c: Array| -> Res {
+                    record(Shape::FillEllipse(
+                        (clamp(x), clamp(y)),
+                        (clamp(rx.max(0)), clamp(ry.max(0))),
+                        to_color(c)?,
+                    ));
+                    Ok(())
+                },
+            )
+            .register_fn(
+                "stroke_ellipse",
+                |x: INT, y: INT, rx: INT, ry: INT, c: Array| -> Res {
+                    record(Shape::StrokeEllipse(
+                        (clamp(x), clamp(y)),
+                        (clamp(rx.max(0)), clamp(ry.max(0))),
+                        to_color(c)?,
+                    ));
+                    Ok(())
+                },
+            )
+            .register_fn(
+                "line",
+                |x0: INT, y0: INT, x1: INT, y1: INT, c: Array| -> Res {
+                    record(Shape::Line(
+                        (x0 as f32, y0 as f32),
+                        (x1 as f32, y1 as f32),
+                        to_color(c)?,
+                    ));
+                    Ok(())
+                },
+            )
+            .register_fn("pixel", |x: INT, y: INT, c: Array| -> Res {
+                record(Shape::Pixel((x, y), to_color(c)?));
+                Ok(())
+            });
+
+        let ast = engine
+            .compile(script)
+            .map_err(|e| ScriptError::Compile(e.to_string()))?;
+
+        let brush = Self { engine, ast };
+        brush
+            .run(
+                (0, 0),
+                (16, 16),
+                1,
+                PixelType::from([128, 128, 128, 255]),
+                0.,
+                0,
+            )
+            .map_err(|e| ScriptError::Runtime(e.to_string()))?;
+
+        Ok(brush)
+    }
+
+    fn run(
+        &self,
+        (sx, sy): (u32, u32),
+        (w, h): (u32, u32),
+        now_depth: u8,
+        color: PixelType,
+        time_elapsed: f32,
+        seed: u64,
+    ) -> Result<Vec<Shape>, Box<EvalAltResult>> {
+        let mut scope = Scope::new();
+        scope
+            .push("x", sx as INT)
+            .push("y", sy as INT)
+            .push("w", w as INT)
+            .push("h", h as INT)
+            .push("depth", now_depth as INT)
+            .push(
+                "color",
+                color
+                    .0
+                    .into_iter()
+                    .map(|c| Dynamic::from_int(c as INT))
+                    .collect::<Array>(),
+            )
+            .push("time", time_elapsed as FLOAT)
+            .push("seed", seed as INT);
+
+        SHAPES.with(|s| s.borrow_mut().clear());
+        let result = self.engine.run_ast_with_scope(&mut scope, &self.ast);
+        let shapes = SHAPES.with(|s| s.take());
+
+        result.map(|_| shapes)
+    }
+}
+
+impl Shape {
+    /// 脚本给的坐标可以任意大，画之前先裁到图像附近：
+    /// 不然 imageproc 会一个像素一个像素地走完整条线，或者在算椭圆时溢出。
+    /// 完全在图像外的形状返回 `None`。
+    fn clipped(self, (iw, ih): (u32, u32)) -> Option<Self> {
+        // 半径和描边宽度最多是图像长边的几倍，再大画出来也一样
+        let limit = 4 * iw.max(ih).max(1) as i64;
+        // [start, start + len) 和 [-pad, end + pad) 的交集
+        let span = |start: i32, len: u32, end: u32, pad: i64| {
+            let lo = (start as i64).max(-pad);
+            let hi = (start as i64 + len as i64).min(end as i64 + pad);
+            (lo < hi).then(|| (lo as i32, (hi - lo) as u32))
+        };
+        let rect = |(x, y): (i32, i32), (w, h): (u32, u32), pad: i64| {
+            let ((x, w), (y, h)) = (span(x, w, iw, pad)?, span(y, h, ih, pad)?);
+            Some(((x, y), (w, h)))
+        };
+        let ellipse = |(cx, cy): (i32, i32), (rx, ry): (i32, i32)| {
+            let (rx, ry) = ((rx as i64).min(limit), (ry as i64).min(limit));
+            let (cx, cy) = (cx as i64, cy as i64);
+            let visible =
+                cx + rx >= 0 && cx - rx < iw as i64 && cy + ry >= 0 && cy - ry < ih as i64;
+            visible.then_some(((cx as i32, cy as i32), (rx as i32, ry as i32)))
+        };
+
+        match self {
+            Shape::FillRect(at, size, c) => {
+                let (at, size) = rect(at, size, 0)?;
+                Some(Shape::FillRect(at, size, c))
+            }
+            Shape::StrokeRect(_, _, 0, _) => None,
+            Shape::StrokeRect(at, size, width, c) => {
+                // 描边在矩形内侧，裁掉的边要留在图像外面，不能画进来
+                let width = width.min(limit as u32);
+                let (at, size) = rect(at, size, width as i64 + 1)?;
+                Some(Shape::StrokeRect(at, size, width, c))
+            }
+            Shape::FillEllipse(center, radii, c) => {
+                let (center, radii) = ellipse(center, radii)?;
+                Some(Shape::FillEllipse(center, radii, c))
+            }
+            Shape::StrokeEllipse(center, radii, c) => {
+                let (center, radii) = ellipse(center, radii)?;
+                Some(Shape::StrokeEllipse(center, radii, c))
+            }
+            Shape::Line(start, end, c) => {
+                let (start, end) = clip_line(start, end, (iw as f32, ih as f32))?;
+                Some(Shape::Line(start, end, c))
+            }
+            Shape::Pixel(..) => Some(self),
+        }
+    }
+}
+
+/// Liang–Barsky：把线段裁到比图像大一圈的范围里，用 f64 算以免远处的端点丢了精度
+fn clip_line(
+    (x0, y0): (f32, f32),
+    (x1, y1): (f32, f32),
+    (w, h): (f32, f32),
+) -> Option<((f32, f32), (f32, f32))> {
+    let (x0, y0, w, h) = (x0 as f64, y0 as f64, w as f64, h as f64);
+    let (dx, dy) = (x1 as f64 - x0, y1 as f64 - y0);
+    let (mut t0, mut t1) = (0f64, 1f64);
+    for (p, q) in [(-dx, x0 + 1.), (dx, w - x0), (-dy, y0 + 1.), (dy, h - y0)] {
+        if p == 0. {
+            if q < 0. {
+                return None;
+            }
+        } else if p < 0. {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    let at = |t: f64| ((x0 + t * dx) as f32, (y0 + t * dy) as f32);
+    (t0 <= t1).then(|| (at(t0), at(t1)))
+}
+
+/// 逐行填充椭圆在图像内的部分（imageproc 的椭圆在半径大时会溢出）
+fn fill_ellipse(img: &mut ImageType, (cx, cy): (i32, i32), (rx, ry): (i32, i32), color: PixelType) {
+    let (w, h) = (img.width() as i32, img.height() as i32);
+    for py in (cy - ry).max(0)..=(cy + ry).min(h - 1) {
+        let t = if ry == 0 {
+            0.
+        } else {
+            (py - cy) as f32 / ry as f32
+        };
+        let half = (rx as f32 * (1. - t * t).max(0.).sqrt()).round() as i32;
+        for px in (cx - half).max(0)..=(cx + half).min(w - 1) {
+            img.put_pixel(px as u32, py as u32, color);
+        }
+    }
+}
+
+fn to_color(c: Array) -> Result<PixelType, Box<EvalAltResult>> {
+    let channels = c
+        .into_iter()
+        .map(|ch| ch.as_int().map(|v| v.clamp(0, 255) as u8))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|t| format!("color channels must be integers, not {t}"))?;
+    match channels[..] {
+        [r, g, b] => Ok(PixelType::from([r, g, b, 255])),
+        [r, g, b, a] => Ok(PixelType::from([r, g, b, a])),
+        _ => Err("colors must be `[r, g, b]` or `[r, g, b, a]`".into()),
+    }
+}
+
+impl Brush for ScriptBrush {
+    fn paint(
+        &self,
+        img: &mut ImageType,
+        params: RenderParams,
+        start_at: (u32, u32),
+        area_size: (u32, u32),
+        time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+    ) {
+        let shapes = match self.run(
+            start_at,
+            area_size,
+            now_depth,
+            color,
+            time_elapsed,
+            params.seed,
+        ) {
+            Ok(shapes) => shapes,
+            Err(e) => {
+                FIRST_ERROR.with(|first| {
+                    first
+                        .borrow_mut()
+                        .get_or_insert_with(|| format!("at ({}, {}): {e}", start_at.0, start_at.1));
+                });
+                return;
+            }
+        };
+
+        let img_size = img.dimensions();
+        for shape in shapes.into_iter().filter_map(|s| s.clipped(img_size)) {
+            match shape {
+                Shape::FillRect((x, y), (w, h), c) => {
+                    draw_filled_rect_mut(img, Rect::at(x, y).of_size(w, h), c)
+                }
+                Shape::StrokeRect(at, size, width, c) => {
+                    let params = RenderParams {
+                        stroke_width: width,
+                        stroke_align: StrokeAlign::Inner,
+                        stroke_style: StrokeStyle::Solid,
+                        ..params
+                    };
+                    draw_rect_outline(img, at, size, params, c)
+                }
+                Shape::FillEllipse(center, radii, c) => fill_ellipse(img, center, radii, c),
+                Shape::StrokeEllipse(center, radii, c) => {
+                    let params = RenderParams {
+                        stroke_width: 1,
+                        stroke_align: StrokeAlign::Inner,
+                        stroke_style: StrokeStyle::Solid,
+                        ..params
+                    };
+                    draw_ellipse_stroke(img, center, radii, params, c)
+                }
+                Shape::Line(start, end, c) => draw_line_segment_mut(img, start, end, c),
+                Shape::Pixel((x, y), c) => {
+                    if (0..img.width() as i64).contains(&x) && (0..img.height() as i64).contains(&y)
+                    {
+                        img.put_pixel(x as u32, y as u32, c)
+                    }
+                }
+            }
+        }
+    }
+
+    fn take_error(&self) -> Option<String> {
+        FIRST_ERROR.with(|first| first.take())
+    }
+
+    fn need_background(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for ScriptBrush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScriptBrush").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn painted(script: &str) -> ImageType {
+        let brush = ScriptBrush::new(script).unwrap();
+        let mut img = ImageType::from_pixel(40, 30, PixelType::from([255, 255, 255, 255]));
+        let re_params = RenderParams::new(
+            PixelType::from([255, 255, 255, 255]),
+            PixelType::from([0, 0, 0, 255]),
+            0,
+        );
+        let color = PixelType::from([128, 128, 128, 255]);
+        brush.paint(&mut img, re_params, (0, 0), (40, 30), 0., 1, color);
+        assert_eq!(brush.take_error(), None);
+        img
+    }
+
+    #[test]
+    fn huge_shapes() {
+        let black = PixelType::from([0, 0, 0, 255]);
+        let white = PixelType::from([255, 255, 255, 255]);
+
+        let img = painted("line(-1000000000, 10, 1000000000, 10, [0, 0, 0]);");
+        assert!((0..40).all(|x| img[(x, 10)] == black));
+        assert!((0..40).all(|x| img[(x, 11)] == white));
+
+        let img = painted("fill_ellipse(20, 15, 1000000000, 2000000000, [0, 0, 0]);");
+        assert!(img.pixels().all(|&p| p == black));
+
+        // 圆心在图像外很远，半径被限制住以后什么都碰不到
+        let img = painted("stroke_ellipse(-2000000000, 15, 1000000000, 5, [0, 0, 0]);");
+        assert!(img.pixels().all(|&p| p == white));
+
+        let img = painted("stroke_rect(-1000000000, 5, 2000000000, 10, 1000000000, [0, 0, 0]);");
+        assert!((0..40).all(|x| img[(x, 10)] == black && img[(x, 20)] == white));
+    }
+}
@@ -0,0 +1,225 @@
+use super::*;
+
+/// What [`analyze_for()`] aims at.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// At least this PSNR (in dB) of the leaves from the image, see [`canvas_psnr()`].
+    Psnr(f64),
+    /// At most this many leaves.
+    Leaves(usize),
+}
+
+/// Perform [`analyze()`] with the thresholds scaled together (keeping their ratio), to reach the target
+/// with as large thresholds (for PSNR) or as small thresholds (for leaves) as possible.
+///
+/// The thresholds are searched by bisection, analyzing the image in the same canvas over and over,
+/// which is cheap since thresholds have only a weak impact on performance.
+/// The canvas ends up with the result of the returned parameters.
+///
+/// If the target is out of reach, the closest result is taken.
+/// If both thresholds are zero, they are scaled as if they were 1.0 and 0.1.
+pub fn analyze_for(
+    img: &ImageType,
+    canvas: CanvasViewMut,
+    ge_params: GenericParams,
+    an_params: AnalyzeParams,
+    target: Target,
+) -> Result<AnalyzeParams, AnalyzeError> {
+    analyze_for_with(img, canvas, ge_params, an_params, target, |_| Ok(()))
+}
+
+/// [`analyze_for()`], with `finish` applied to the canvas after each analysis and before it is measured,
+/// so that the target is reached by the finished canvas, e.g. after [`quantize()`] to a palette.
+pub fn analyze_for_with<E, F>(
+    img: &ImageType,
+    canvas: CanvasViewMut,
+    ge_params: GenericParams,
+    an_params: AnalyzeParams,
+    target: Target,
+    mut finish: F,
+) -> Result<AnalyzeParams, E>
+where
+    E: From<AnalyzeError>,
+    F: FnMut(CanvasViewMut) -> Result<(), E>,
+{
+    const ITERATIONS: usize = 14;
+    // 阈值再大也没有意义了，所有的子块都会被合并
+    const THRES_MAX: f32 = 256.;
+    // 在对数上二分，最小的缩放（这里当作 0）是最大的 2^-16
+    const SPAN: f32 = 16.;
+
+    let (base_ay, base_cbcr) = match (an_params.thres_ay, an_params.thres_cbcr) {
+        (ay, cbcr) if ay <= 0. && cbcr <= 0. => (1., 0.1),
+        (ay, cbcr) => (ay.max(0.), cbcr.max(0.)),
+    };
+    let scaled = |s: f32| AnalyzeParams {
+        thres_ay: base_ay * s,
+        thres_cbcr: base_cbcr * s,
+        ..an_params
+    };
+    let smallest = [base_ay, base_cbcr]
+        .into_iter()
+        .filter(|&t| t > 0.)
+        .fold(f32::MAX, f32::min);
+
+    // 缩放得越大，叶子越少，PSNR 也越低
+    let mut is_enough = |s: f32| -> Result<bool, E> {
+        analyze(img, canvas, ge_params, scaled(s))?;
+        finish(canvas)?;
+        Ok(match target {
+            Target::Psnr(psnr) => canvas_psnr(img, canvas, ge_params).is_ok_and(|p| p >= psnr),
+            Target::Leaves(n) => count_leaves(canvas, img.dimensions(), ge_params) <= n,
+        })
+    };
+
+    let (e_min, e_max) = (
+        (THRES_MAX / smallest).log2() - SPAN,
+        (THRES_MAX / smallest).log2(),
+    );
+    let at = |e: f32| match e <= e_min {
+        true => 0.,
+        false => e.exp2(),
+    };
+
+    let best = match (target, is_enough(0.)?) {
+        // 最多细节的时候也达不到，或者已经达到了
+        (Target::Psnr(_), false) | (Target::Leaves(_), true) => 0.,
+        (Target::Psnr(_), true) if is_enough(at(e_max))? => at(e_max),
+        _ => {
+            // Psnr 时 `lo` 总是达标的，Leaves 时 `hi` 总是达标的
+            let (mut lo, mut hi) = (e_min, e_max);
+            for _ in 0..ITERATIONS {
+                let mid = (lo + hi) / 2.;
+                match (is_enough(at(mid))?, target) {
+                    (true, Target::Psnr(_)) | (false, Target::Leaves(_)) => lo = mid,
+                    (false, Target::Psnr(_)) | (true, Target::Leaves(_)) => hi = mid,
+                }
+            }
+            match target {
+                Target::Psnr(_) => at(lo),
+                Target::Leaves(_) => at(hi),
+            }
+        }
+    };
+
+    let best = scaled(best);
+    analyze(img, canvas, ge_params, best)?;
+    finish(canvas)?;
+    Ok(best)
+}
+
+fn count_leaves(canvas: CanvasView, img_size: (u32, u32), ge_params: GenericParams) -> usize {
+    let mut n = 0;
+    for_each_leaf(canvas, img_size, ge_params, |_| n += 1);
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: (u32, u32) = (64, 48);
+
+    /// 渐变上加一点噪声，阈值不同，叶子的数量和 PSNR 也不同
+    fn image() -> ImageType {
+        let mut state = 7u32;
+        ImageType::from_fn(SIZE.0, SIZE.1, |x, y| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let noise = (state >> 24) as u8 / 16;
+            PixelType::from([(x * 4) as u8 + noise, (y * 5) as u8, 100 + noise, 255])
+        })
+    }
+
+    fn params() -> (GenericParams, AnalyzeParams) {
+        let an_params = AnalyzeParams {
+            thres_ay: 1.,
+            thres_cbcr: 0.1,
+            merge_method: MergeMethod::StDev,
+        };
+        (GenericParams::new((4, 3), 6), an_params)
+    }
+
+    fn searched(target: Target) -> (Vec<CanvasPixel>, GenericParams) {
+        let (ge_params, an_params) = params();
+        let mut canvas = vec![(0u8, SampleType::zeros()); (SIZE.0 * SIZE.1) as usize];
+        analyze_for(&image(), &mut canvas, ge_params, an_params, target).unwrap();
+        (canvas, ge_params)
+    }
+
+    #[test]
+    fn leaves() {
+        let (canvas, ge_params) = searched(Target::Leaves(usize::MAX));
+        let most = count_leaves(&canvas, SIZE, ge_params);
+        for n in [12, 100, most / 2] {
+            let (canvas, ge_params) = searched(Target::Leaves(n));
+            let leaves = count_leaves(&canvas, SIZE, ge_params);
+            // 阈值是连续变化的，叶子数应该离目标不远
+            assert!(leaves <= n && leaves * 2 > n, "{leaves} leaves for {n}");
+        }
+    }
+
+    #[test]
+    fn psnr() {
+        let psnr = |target| {
+            let (canvas, ge_params) = searched(target);
+            canvas_psnr(&image(), &canvas, ge_params).unwrap()
+        };
+        // 达不到时取最接近的
+        let (best, worst) = (psnr(Target::Psnr(f64::INFINITY)), psnr(Target::Leaves(1)));
+        assert!(worst + 4. < best, "{worst} to {best} dB");
+        for target in [worst + 1., (worst + best) / 2., best - 1.] {
+            let reached = psnr(Target::Psnr(target));
+            assert!(
+                (target..target + 2.).contains(&reached),
+                "{reached} dB for {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn finished() {
+        let img = image();
+        let (ge_params, an_params) = params();
+        let palette = Palette::parse("#000, #444, #888, #ccc, #fff, red, lime, blue").unwrap();
+        let mut canvas = vec![(0u8, SampleType::zeros()); (SIZE.0 * SIZE.1) as usize];
+
+        // 量化后最多能达到的 PSNR
+        analyze(
+            &img,
+            &mut canvas,
+            ge_params,
+            AnalyzeParams {
+                thres_ay: 0.,
+                thres_cbcr: 0.,
+                ..an_params
+            },
+        )
+        .unwrap();
+        quantize(&mut canvas, SIZE, ge_params, &palette);
+        let target = canvas_psnr(&img, &canvas, ge_params).unwrap() - 0.5;
+
+        let mut finishes = 0;
+        let snap = |canvas: CanvasViewMut| {
+            finishes += 1;
+            quantize(canvas, SIZE, ge_params, &palette);
+            Ok::<_, AnalyzeError>(())
+        };
+        let found = analyze_for_with(
+            &img,
+            &mut canvas,
+            ge_params,
+            an_params,
+            Target::Psnr(target),
+            snap,
+        )
+        .unwrap();
+        assert!(finishes > 1 && found.thres_ay > 0.);
+
+        // 最终的画布也是量化过的
+        let mut colors = Vec::new();
+        for_each_leaf(&canvas, SIZE, ge_params, |leaf| colors.push(leaf.color));
+        assert!(colors.iter().all(|c| palette.colors.contains(c)));
+        assert!(canvas_psnr(&img, &canvas, ge_params).unwrap() >= target);
+    }
+}
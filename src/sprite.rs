@@ -0,0 +1,158 @@
+use super::*;
+
+use std::{fmt, path::Path};
+
+use image::Pixel;
+
+/// A brush that stamps a scaled sprite into each node, colored by the node's color.
+///
+/// If more than one sprite is given, the N-th sprite is used for nodes at depth N,
+/// and the last one is used for all the deeper nodes.
+#[derive(Clone)]
+pub struct SpriteBrush {
+    sprites: Vec<ImageType>,
+    /// How to scale the sprite to the size of the node.
+    pub filter: SpriteFilter,
+    /// How to combine the sprite with the color of the node.
+    pub blend: SpriteBlend,
+}
+
+/// Specifies how to scale sprites.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum SpriteFilter {
+    Nearest,
+    Bilinear,
+}
+
+/// Specifies how to combine sprites with the color of nodes.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum SpriteBlend {
+    /// Multiply each channel of the sprite by the color.
+    Multiply,
+    /// Keep only the luminance and the alpha of the sprite, then multiply by the color.
+    Tint,
+}
+
+impl SpriteBrush {
+    pub fn new(sprites: Vec<ImageType>, filter: SpriteFilter, blend: SpriteBlend) -> Self {
+        Self {
+            sprites,
+            filter,
+            blend,
+        }
+    }
+
+    /// Load sprites from files, in order of depth.
+    pub fn open<P: AsRef<Path>>(
+        paths: &[P],
+        filter: SpriteFilter,
+        blend: SpriteBlend,
+    ) -> image::ImageResult<Self> {
+        Ok(Self::new(
+            paths
+                .iter()
+                .map(|p| Ok(image::open(p)?.into_rgba8()))
+                .collect::<image::ImageResult<_>>()?,
+            filter,
+            blend,
+        ))
+    }
+
+    fn sample(&self, sprite: &ImageType, u: f32, v: f32) -> PixelType {
+        let (sw, sh) = sprite.dimensions();
+        let (u, v) = (u * sw as f32, v * sh as f32);
+
+        match self.filter {
+            SpriteFilter::Nearest => {
+                *sprite.get_pixel((u as u32).min(sw - 1), (v as u32).min(sh - 1))
+            }
+            SpriteFilter::Bilinear => {
+                let (u, v) = ((u - 0.5).max(0.), (v - 0.5).max(0.));
+                let (x0, y0) = ((u as u32).min(sw - 1), (v as u32).min(sh - 1));
+                let (x1, y1) = ((x0 + 1).min(sw - 1), (y0 + 1).min(sh - 1));
+                let (fx, fy) = (u.fract(), v.fract());
+
+                let at = |x, y| SampleType::from(sprite.get_pixel(x, y).0).cast::<f32>();
+                let top = at(x0, y0) * (1. - fx) + at(x1, y0) * fx;
+                let bottom = at(x0, y1) * (1. - fx) + at(x1, y1) * fx;
+                let mixed = (top * (1. - fy) + bottom * fy).map(|c| c.round());
+
+                PixelType::from(Into::<[DepthType; CHANNEL_COUNT]>::into(
+                    mixed.try_cast::<DepthType>().unwrap(),
+                ))
+            }
+        }
+    }
+}
+
+impl Brush for SpriteBrush {
+    fn paint(
+        &self,
+        img: &mut ImageType,
+        _params: RenderParams,
+        (sx, sy): (u32, u32),
+        (w, h): (u32, u32),
+        _time_elapsed: f32,
+        now_depth: u8,
+        color: PixelType,
+    ) {
+        let sprite = match self.sprites.len() {
+            0 => return,
+            n => &self.sprites[(now_depth as usize).clamp(1, n) - 1],
+        };
+        if sprite.width() == 0 || sprite.height() == 0 {
+            return;
+        }
+
+        let mul = |a: u8, b: u8| ((a as u16 * b as u16 + 127) / 255) as u8;
+        let [cr, cg, cb, ca] = color.0;
+
+        for y in 0..h {
+            for x in 0..w {
+                let s = self.sample(
+                    sprite,
+                    (x as f32 + 0.5) / w as f32,
+                    (y as f32 + 0.5) / h as f32,
+                );
+                let stamp = match self.blend {
+                    SpriteBlend::Multiply => PixelType::from([
+                        mul(s[0], cr),
+                        mul(s[1], cg),
+                        mul(s[2], cb),
+                        mul(s[3], ca),
+                    ]),
+                    SpriteBlend::Tint => {
+                        let l = s.to_luma()[0];
+                        PixelType::from([mul(l, cr), mul(l, cg), mul(l, cb), mul(s[3], ca)])
+                    }
+                };
+                img.get_pixel_mut(sx + x, sy + y).blend(&stamp);
+            }
+        }
+    }
+
+    fn need_background(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for SpriteBrush {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpriteBrush")
+            .field(
+                "sprites",
+                &self
+                    .sprites
+                    .iter()
+                    .map(|s| s.dimensions())
+                    .collect::<Vec<_>>(),
+            )
+            .field("filter", &self.filter)
+            .field("blend", &self.blend)
+            .finish()
+    }
+}
@@ -0,0 +1,128 @@
+use super::*;
+
+use std::fmt::Write;
+
+/// Parameters required by [`render_text()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextParams {
+    /// The width of the result, in characters.
+    /// The number of rows follows the aspect ratio of the image, assuming characters are twice as tall as wide.
+    pub columns: u32,
+    /// Specifies the characters used to depict each cell.
+    pub charset: TextCharset,
+    /// Color the characters with ANSI truecolor escapes.
+    pub ansi: bool,
+}
+
+/// Specifies the characters used by [`render_text()`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum TextCharset {
+    /// Block elements. With ANSI colors, each character depicts two cells using "▀".
+    Blocks,
+    /// A density ramp of ASCII characters.
+    Ramp,
+}
+
+const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+const RAMP: [char; 10] = [' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+
+/// Depict the canvas from [`analyze()`] as text art, one line per row.
+///
+/// # 🚧 Panics 🚧
+///
+/// The image size and the canvas must be exactly the same as those passed to [`analyze()`]!
+pub fn render_text(
+    canvas: CanvasView,
+    img_size: (u32, u32),
+    ge_params: GenericParams,
+    te_params: TextParams,
+) -> String {
+    let TextParams {
+        columns,
+        charset,
+        ansi,
+    } = te_params;
+
+    let (img_w, img_h) = img_size;
+    let cols = columns.max(1);
+    let rows = ((cols as f64 * img_h as f64 / img_w as f64 / 2.).round() as u32).max(1);
+    let half_blocks = ansi && charset == TextCharset::Blocks;
+
+    // 每个格子取中心所在叶子的颜色
+    let (grid_w, grid_h) = (cols, if half_blocks { rows * 2 } else { rows });
+    let mut grid = vec![PixelType::from([0; CHANNEL_COUNT]); (grid_w * grid_h) as usize];
+
+    let cells = |start: u32, length: u32, full: u32, grid: u32| {
+        let at = |p: u32| (p as f64 * grid as f64 / full as f64 - 0.5).ceil().max(0.) as u32;
+        at(start)..at(start + length).min(grid)
+    };
+    for_each_leaf(
+        canvas,
+        img_size,
+        ge_params,
+        |Leaf {
+             start_at: (sx, sy),
+             area_size: (w, h),
+             depth: _,
+             color,
+         }| {
+            let color = premultiply(color);
+            for gy in cells(sy, h, img_h, grid_h) {
+                for gx in cells(sx, w, img_w, grid_w) {
+                    grid[(gy * grid_w + gx) as usize] = color;
+                }
+            }
+        },
+    );
+
+    let mut text = String::new();
+    let mut last = (None, None);
+    for row in 0..rows {
+        for col in 0..cols {
+            if half_blocks {
+                let top = grid[(row * 2 * grid_w + col) as usize];
+                let bottom = grid[((row * 2 + 1) * grid_w + col) as usize];
+                if last.0 != Some(top) {
+                    write!(text, "\x1b[38;2;{};{};{}m", top[0], top[1], top[2]).unwrap();
+                }
+                if last.1 != Some(bottom) {
+                    write!(text, "\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]).unwrap();
+                }
+                last = (Some(top), Some(bottom));
+                text.push('▀');
+            } else {
+                let color = grid[(row * grid_w + col) as usize];
+                if ansi && last.0 != Some(color) {
+                    write!(text, "\x1b[38;2;{};{};{}m", color[0], color[1], color[2]).unwrap();
+                    last.0 = Some(color);
+                }
+                text.push(match charset {
+                    TextCharset::Blocks => shade(&SHADES, color),
+                    TextCharset::Ramp => shade(&RAMP, color),
+                });
+            }
+        }
+        if ansi {
+            text.push_str("\x1b[0m");
+            last = (None, None);
+        }
+        text.push('\n');
+    }
+
+    text
+}
+
+/// 透明的地方当作黑色
+fn premultiply(color: PixelType) -> PixelType {
+    let [r, g, b, a] = color.0;
+    let mul = |c: u8| ((c as u16 * a as u16 + 127) / 255) as u8;
+    PixelType::from([mul(r), mul(g), mul(b), 255])
+}
+
+/// ITU-R BT.709
+fn shade(chars: &[char], color: PixelType) -> char {
+    let luma = 0.2126 * color[0] as f32 + 0.7152 * color[1] as f32 + 0.0722 * color[2] as f32;
+    chars[(luma / 255. * (chars.len() - 1) as f32).round() as usize]
+}
@@ -4,6 +4,51 @@ pub fn pos(img_w: u32, sx: u32, sy: u32) -> usize {
     (img_w * sy + sx) as usize
 }
 
+/// 渐变存放在叶子内部：左上角右边一格是 `dx`，下边一格是 `dy`。
+///
+/// 仅当叶子的宽和高都不小于 2 时可用。
+pub fn gradient_slots(img_w: u32, (sx, sy): (u32, u32)) -> (usize, usize) {
+    (pos(img_w, sx + 1, sy), pos(img_w, sx, sy + 1))
+}
+
+/// 精度减半，以存进 u8
+pub fn encode_slope(slope: SampleAltType) -> SampleType {
+    slope.map(|v| (v / 2. + 128.).round().clamp(0., 255.) as u8)
+}
+
+pub fn decode_slope(slope: SampleType) -> SampleAltType {
+    slope.map(|v| (v as f32 - 128.) * 2.)
+}
+
+/// 把分析时的坐标映射到输出图像上，映射后相邻的矩形仍然严丝合缝
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rescale {
+    from: (u32, u32),
+    to: (u32, u32),
+}
+
+impl Rescale {
+    pub fn new(from: (u32, u32), to: (u32, u32)) -> Self {
+        Self { from, to }
+    }
+
+    pub fn rect(&self, (sx, sy): (u32, u32), (w, h): (u32, u32)) -> ((u32, u32), (u32, u32)) {
+        if self.from == self.to {
+            return ((sx, sy), (w, h));
+        }
+        let map = |v: u32, from: u32, to: u32| (v as u64 * to as u64 / from as u64) as u32;
+        let (x0, x1) = (
+            map(sx, self.from.0, self.to.0),
+            map(sx + w, self.from.0, self.to.0),
+        );
+        let (y0, y1) = (
+            map(sy, self.from.1, self.to.1),
+            map(sy + h, self.from.1, self.to.1),
+        );
+        ((x0, y0), (x1 - x0, y1 - y0))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Tile {
     pub start_at: (u32, u32),
@@ -12,7 +57,7 @@ pub struct Tile {
 }
 
 /// "И" order.
-pub fn div_grid<'a>(
+pub fn div_grid(
     (img_w, img_h): (u32, u32),
     (ratio_w, ratio_h): (u8, u8),
     max_depth: u8,
@@ -73,3 +118,84 @@ pub fn div_quad(
     ]
     .into_iter()
 }
+
+/// SplitMix64. Seeded by the seed and the node, so that results are reproducible across runs and threads.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64, (sx, sy): (u32, u32), depth: u8) -> Self {
+        // 每混入一部分就打散一次，相近的种子与相近的位置也不会得到相关的序列
+        let mut rng = Self(seed);
+        for part in [(sx as u64) << 32 | sy as u64, depth as u64] {
+            rng.0 = rng.next_u64() ^ part;
+        }
+        rng.0 = rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// In `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// In `-1.0..1.0`.
+    pub fn next_signed(&mut self) -> f32 {
+        self.next_f32() * 2. - 1.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 把图像切到 `depth` 层，这些子块正好铺满图像
+    fn partition(img_size: (u32, u32), depth: u8) -> Vec<((u32, u32), (u32, u32))> {
+        fn go(
+            rects: &mut Vec<((u32, u32), (u32, u32))>,
+            rect: ((u32, u32), (u32, u32)),
+            depth: u8,
+        ) {
+            match depth {
+                1 => rects.push(rect),
+                _ => div_quad(rect.0, rect.1).for_each(|quad| go(rects, quad, depth - 1)),
+            }
+        }
+        let mut rects = Vec::new();
+        for tile in div_grid(img_size, (4, 3), depth) {
+            go(&mut rects, (tile.start_at, tile.area_size), depth);
+        }
+        rects
+    }
+
+    #[test]
+    fn rescale_adjoins() {
+        let from = (67, 49);
+        for to in [from, (201, 150), (640, 480), (33, 20), (7, 5)] {
+            let rescale = Rescale::new(from, to);
+            for depth in 1..=5 {
+                // 映射后仍然不重叠、没有缝隙
+                let mut covered = vec![0u8; (to.0 * to.1) as usize];
+                for (start_at, area_size) in partition(from, depth) {
+                    let ((sx, sy), (w, h)) = rescale.rect(start_at, area_size);
+                    for y in sy..sy + h {
+                        for x in sx..sx + w {
+                            covered[pos(to.0, x, y)] += 1;
+                        }
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&c| c == 1),
+                    "{from:?} to {to:?} at depth {depth}"
+                );
+            }
+        }
+    }
+}